@@ -0,0 +1,259 @@
+use serde::{Deserialize, Serialize};
+
+use crate::camera::stream_dir;
+
+/// How a camera is captured & encoded to HLS.
+/// The `[encoding]` section applies to every camera unless it has its own `[cameras.encoding]`.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct EncodingConfig {
+    /// V4L2 input format (mjpeg, yuyv422, h264...), ignored by network sources
+    pub input_format: String,
+    /// Output size like "640x360", the capture size is kept when unset
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub resolution: Option<String>,
+    /// Output framerate, the capture framerate is kept when unset
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub framerate: Option<u32>,
+    pub codec: String,
+    pub preset: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tune: Option<String>,
+    /// Constant quality mode (0-51), can't be used with `bitrate`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub crf: Option<u32>,
+    /// Target bitrate like "2M" or "800k", can't be used with `crf`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bitrate: Option<String>,
+    /// Frames between two keyframes
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub gop_size: Option<u32>,
+    /// HLS segment length in seconds
+    pub hls_time: u32,
+    /// Segments kept in the HLS playlist
+    pub hls_list_size: u32,
+}
+
+impl Default for EncodingConfig {
+    fn default() -> Self {
+        EncodingConfig {
+            input_format: "mjpeg".to_string(),
+            resolution: None,
+            framerate: None,
+            codec: "libx264".to_string(),
+            preset: "ultrafast".to_string(),
+            tune: Some("zerolatency".to_string()),
+            crf: None,
+            bitrate: None,
+            gop_size: None,
+            hls_time: 4,
+            hls_list_size: 5,
+        }
+    }
+}
+
+const X26X_PRESETS: [&str; 10] = [
+    "ultrafast",
+    "superfast",
+    "veryfast",
+    "faster",
+    "fast",
+    "medium",
+    "slow",
+    "slower",
+    "veryslow",
+    "placebo",
+];
+
+/// Parses a "WIDTHxHEIGHT" string
+pub fn parse_resolution(resolution: &str) -> Option<(u32, u32)> {
+    let (width, height) = resolution.split_once('x')?;
+    let width = width.trim().parse::<u32>().ok()?;
+    let height = height.trim().parse::<u32>().ok()?;
+    if width == 0 || height == 0 {
+        return None;
+    }
+    Some((width, height))
+}
+
+/// Bits per second of an ffmpeg bitrate like "2M", "800k" or "500000"
+pub fn parse_bitrate(bitrate: &str) -> Option<u64> {
+    let (digits, unit) = match bitrate.char_indices().last()? {
+        (i, 'k' | 'K') => (&bitrate[..i], 1_000),
+        (i, 'M') => (&bitrate[..i], 1_000_000),
+        _ => (bitrate, 1),
+    };
+    if digits.is_empty() || !digits.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    digits
+        .parse::<u64>()
+        .ok()?
+        .checked_mul(unit)
+        .filter(|bits| *bits > 0)
+}
+
+fn is_ffmpeg_name(value: &str) -> bool {
+    !value.is_empty()
+        && value
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+}
+
+impl EncodingConfig {
+    /// Checks the values before they reach ffmpeg, returns a human readable error otherwise
+    pub fn check(&self) -> Result<(), String> {
+        if !is_ffmpeg_name(&self.input_format) {
+            return Err(format!("invalid input_format \"{}\"", self.input_format));
+        }
+        if !is_ffmpeg_name(&self.codec) {
+            return Err(format!("invalid codec \"{}\"", self.codec));
+        }
+        if let Some(resolution) = &self.resolution {
+            match parse_resolution(resolution) {
+                // x264 & most hardware encoders refuse odd sizes with yuv420p
+                Some((width, height)) if width % 2 == 0 && height % 2 == 0 => {}
+                Some(_) => return Err(format!("resolution \"{}\" must be even", resolution)),
+                None => {
+                    return Err(format!(
+                        "resolution \"{}\" should look like 1280x720",
+                        resolution
+                    ));
+                }
+            }
+        }
+        if self.framerate == Some(0) {
+            return Err("framerate must be greater than 0".to_string());
+        }
+        if (self.codec == "libx264" || self.codec == "libx265")
+            && !X26X_PRESETS.contains(&self.preset.as_str())
+        {
+            return Err(format!(
+                "preset \"{}\" isn't one of {}",
+                self.preset,
+                X26X_PRESETS.join(", ")
+            ));
+        }
+        if self.crf.is_some() && self.bitrate.is_some() {
+            return Err("crf and bitrate can't be used together".to_string());
+        }
+        if let Some(crf) = self.crf
+            && crf > 51
+        {
+            return Err(format!("crf {} must be between 0 and 51", crf));
+        }
+        if let Some(bitrate) = &self.bitrate
+            && parse_bitrate(bitrate).is_none()
+        {
+            return Err(format!(
+                "bitrate \"{}\" should look like 2M or 800k",
+                bitrate
+            ));
+        }
+        if self.gop_size == Some(0) {
+            return Err("gop_size must be greater than 0".to_string());
+        }
+        if self.hls_time == 0 {
+            return Err("hls_time must be at least 1 second".to_string());
+        }
+        if self.hls_list_size == 0 {
+            return Err("hls_list_size must be at least 1".to_string());
+        }
+        Ok(())
+    }
+
+    /// ffmpeg arguments placed after the input, ending with the HLS playlist path
+    pub fn output_args(&self, camera_id: &str) -> Vec<String> {
+        let mut args: Vec<String> = vec!["-c:v".into(), self.codec.clone()];
+        if self.codec.starts_with("libx26") {
+            args.extend(["-preset".into(), self.preset.clone()]);
+            if let Some(tune) = &self.tune {
+                args.extend(["-tune".into(), tune.clone()]);
+            }
+        }
+        if let Some(crf) = self.crf {
+            args.extend(["-crf".into(), crf.to_string()]);
+        }
+        if let Some(bitrate) = &self.bitrate {
+            args.extend([
+                "-b:v".into(),
+                bitrate.clone(),
+                "-maxrate".into(),
+                bitrate.clone(),
+            ]);
+            // The rate control needs a buffer to enforce maxrate, 2 seconds of video
+            if let Some(bits) = parse_bitrate(bitrate) {
+                args.extend(["-bufsize".into(), (bits * 2).to_string()]);
+            }
+        }
+        if let Some(gop_size) = self.gop_size {
+            args.extend(["-g".into(), gop_size.to_string()]);
+        }
+        if let Some((width, height)) = self.resolution.as_deref().and_then(parse_resolution) {
+            args.extend(["-vf".into(), format!("scale={}:{}", width, height)]);
+        }
+        if let Some(framerate) = self.framerate {
+            args.extend(["-r".into(), framerate.to_string()]);
+        }
+        args.extend(
+            [
+                // Browsers only play 4:2:0 H.264, YUYV webcams would give 4:2:2 otherwise
+                "-pix_fmt",
+                "yuv420p",
+                "-f",
+                "hls",
+                "-hls_flags",
+                "delete_segments+split_by_time", // +independent_segments
+                "-hls_segment_type",
+                "fmp4",
+            ]
+            .map(String::from),
+        );
+        args.extend([
+            "-hls_list_size".into(),
+            self.hls_list_size.to_string(),
+            "-hls_time".into(),
+            self.hls_time.to_string(),
+        ]);
+        args.push(format!("{}/stream.m3u8", stream_dir(camera_id)));
+        args
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_bitrates_with_one_unit() {
+        assert_eq!(parse_bitrate("2M"), Some(2_000_000));
+        assert_eq!(parse_bitrate("800k"), Some(800_000));
+        assert_eq!(parse_bitrate("800K"), Some(800_000));
+        assert_eq!(parse_bitrate("500000"), Some(500_000));
+    }
+
+    #[test]
+    fn rejects_repeated_or_mixed_units() {
+        for bitrate in ["2kM", "5MMM", "M", "", "2 M", "1.5M", "0", "0k", "-2M"] {
+            assert_eq!(parse_bitrate(bitrate), None, "{}", bitrate);
+        }
+    }
+
+    #[test]
+    fn bitrate_sets_a_buffer_size() {
+        let config = EncodingConfig {
+            bitrate: Some("2M".to_string()),
+            ..EncodingConfig::default()
+        };
+        assert!(config.check().is_ok());
+        let args = config.output_args("cam0");
+        let bufsize = args.iter().position(|arg| arg == "-bufsize").unwrap();
+        assert_eq!(args[bufsize + 1], "4000000");
+
+        let config = EncodingConfig {
+            bitrate: Some("5MMM".to_string()),
+            ..EncodingConfig::default()
+        };
+        assert!(config.check().is_err());
+    }
+}
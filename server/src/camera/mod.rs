@@ -0,0 +1,180 @@
+use serde::{Deserialize, Serialize};
+use std::{collections::HashSet, fs};
+
+use crate::camera::encoding::{EncodingConfig, parse_resolution};
+use crate::camera::source::{CameraSource, input_args};
+use crate::camera::supervisor::StreamStatuses;
+use crate::live::LiveSender;
+use crate::movement_detector::recording::RecordingConfig;
+use crate::movement_detector::settings::DetectionConfig;
+use crate::movement_detector::zones::{DetectionZone, check_zones};
+pub mod encoding;
+pub mod probe;
+pub mod source;
+pub mod supervisor;
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct CameraConfig {
+    pub id: String,
+    pub name: String,
+    pub device: String,
+    #[serde(default)]
+    pub source: CameraSource,
+    #[serde(default = "default_resolution")]
+    pub resolution: String,
+    #[serde(default)]
+    pub framerate: Option<u32>,
+    /// Overrides the global [encoding] section for this camera
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub encoding: Option<EncodingConfig>,
+    #[serde(default)]
+    pub detection: DetectionConfig,
+    /// Include / exclude polygons for movement detection, the whole frame is watched when empty
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub zones: Vec<DetectionZone>,
+    #[serde(default)]
+    pub recording: RecordingConfig,
+}
+
+fn default_resolution() -> String {
+    "1280x720".to_string()
+}
+
+impl CameraConfig {
+    pub fn from_device(id: &str, device: &str) -> CameraConfig {
+        CameraConfig {
+            id: id.to_string(),
+            name: id.to_string(),
+            device: device.to_string(),
+            source: CameraSource::V4l2,
+            resolution: default_resolution(),
+            framerate: None,
+            encoding: None,
+            detection: DetectionConfig::default(),
+            zones: vec![],
+            recording: RecordingConfig::default(),
+        }
+    }
+
+    pub fn encoding_or(&self, default: &EncodingConfig) -> EncodingConfig {
+        self.encoding.clone().unwrap_or_else(|| default.clone())
+    }
+}
+
+/// HLS output folder of a camera, served under /protected/stream/<camera_id>/
+pub fn stream_dir(camera_id: &str) -> String {
+    format!("./static/stream/{}", camera_id)
+}
+
+/// Clips & index.json folder of a camera, served under /protected/clips/<camera_id>/
+pub fn clips_dir(camera_id: &str) -> String {
+    format!("./static/clips/{}", camera_id)
+}
+
+/// Continuous recordings of a camera, served under /protected/recordings/<camera_id>/
+pub fn recordings_dir(camera_id: &str) -> String {
+    format!("./static/recordings/{}", camera_id)
+}
+
+/// Finished stream segments kept for the pre-roll & running recordings, not served
+pub fn archive_dir(camera_id: &str) -> String {
+    format!("./static/archive/{}", camera_id)
+}
+
+/// Camera ids end up in paths and URLs so we only accept a safe subset of characters.
+pub fn check_cameras(cameras: &[CameraConfig], default_encoding: &EncodingConfig) {
+    if cameras.is_empty() {
+        panic!("No camera configured, please add a [[cameras]] entry to config.toml");
+    }
+    let mut ids = HashSet::new();
+    for camera in cameras {
+        let valid_id = !camera.id.is_empty()
+            && camera
+                .id
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+        if !valid_id {
+            panic!(
+                "Invalid camera id \"{}\" : only letters, digits, '-' and '_' are allowed",
+                camera.id
+            );
+        }
+        if !ids.insert(camera.id.as_str()) {
+            panic!("Camera id \"{}\" is used more than once", camera.id);
+        }
+        if let Err(err) = camera.source.check(&camera.device) {
+            panic!("Invalid device for camera \"{}\" : {}", camera.id, err);
+        }
+        if parse_resolution(&camera.resolution).is_none() {
+            panic!(
+                "Invalid resolution \"{}\" for camera \"{}\", it should look like 1280x720",
+                camera.resolution, camera.id
+            );
+        }
+        if let Err(err) = camera.encoding_or(default_encoding).check() {
+            panic!(
+                "Invalid encoding settings for camera \"{}\" : {}",
+                camera.id, err
+            );
+        }
+        if let Err(err) = camera.detection.check() {
+            panic!(
+                "Invalid detection settings for camera \"{}\" : {}",
+                camera.id, err
+            );
+        }
+        if let Err(err) = check_zones(&camera.zones) {
+            panic!("Invalid zones for camera \"{}\" : {}", camera.id, err);
+        }
+        if let Err(err) = camera.recording.check() {
+            panic!(
+                "Invalid recording settings for camera \"{}\" : {}",
+                camera.id, err
+            );
+        }
+    }
+}
+
+pub fn start_ffmpeg_webcam_streaming(
+    mut camera: CameraConfig,
+    mut encoding: EncodingConfig,
+    statuses: StreamStatuses,
+    live: LiveSender,
+) {
+    if camera.source == CameraSource::V4l2 {
+        match probe::probe_device(&camera.device) {
+            Ok(device) => probe::fit_capture_mode(&mut camera, &mut encoding, &device),
+            Err(err) => println!(
+                "[{}] Warning: Couldn't probe {} : {}",
+                camera.id, camera.device, err
+            ),
+        }
+    }
+
+    let stream_dir = stream_dir(&camera.id);
+    let _ = fs::remove_dir_all(&stream_dir);
+    match fs::create_dir_all(&stream_dir) {
+        Ok(_) => println!("Warning: (re)created {}", stream_dir),
+        Err(_) => {
+            fs::exists(&stream_dir).unwrap_or_else(|_| {
+                panic!(
+                    "FATAL: Couldn't create {} please check permissions",
+                    stream_dir
+                )
+            });
+        }
+    }
+
+    let args = ffmpeg_args(&camera, &encoding);
+    supervisor::start_ffmpeg_supervisor(camera, args, statuses, live);
+}
+
+fn ffmpeg_args(camera: &CameraConfig, encoding: &EncodingConfig) -> Vec<String> {
+    let mut args: Vec<String> = ["-hide_banner", "-nostats", "-loglevel", "warning"]
+        .map(String::from)
+        .to_vec();
+    args.extend(input_args(camera, encoding));
+    args.extend(["-vsync", "0"].map(String::from));
+    args.extend(encoding.output_args(&camera.id));
+    args
+}
@@ -0,0 +1,339 @@
+use serde::Serialize;
+use std::{fs, io, os::fd::AsRawFd};
+
+use crate::camera::{
+    CameraConfig,
+    encoding::{EncodingConfig, parse_resolution},
+};
+
+// ioctl numbers & structs from linux/videodev2.h
+const VIDIOC_QUERYCAP: u32 = 0x8068_5600;
+const VIDIOC_ENUM_FMT: u32 = 0xC040_5602;
+const VIDIOC_ENUM_FRAMESIZES: u32 = 0xC02C_564A;
+const VIDIOC_ENUM_FRAMEINTERVALS: u32 = 0xC034_564B;
+
+const V4L2_BUF_TYPE_VIDEO_CAPTURE: u32 = 1;
+const V4L2_CAP_VIDEO_CAPTURE: u32 = 0x0000_0001;
+const V4L2_CAP_DEVICE_CAPS: u32 = 0x8000_0000;
+const V4L2_FRMSIZE_TYPE_DISCRETE: u32 = 1;
+const V4L2_FRMIVAL_TYPE_DISCRETE: u32 = 1;
+
+#[repr(C)]
+#[derive(Default)]
+struct V4l2Capability {
+    driver: [u8; 16],
+    card: [u8; 32],
+    bus_info: [u8; 32],
+    version: u32,
+    capabilities: u32,
+    device_caps: u32,
+    reserved: [u32; 3],
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct V4l2FmtDesc {
+    index: u32,
+    typ: u32,
+    flags: u32,
+    description: [u8; 32],
+    pixelformat: u32,
+    mbus_code: u32,
+    reserved: [u32; 3],
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct V4l2FrmSizeEnum {
+    index: u32,
+    pixel_format: u32,
+    typ: u32,
+    // discrete: width, height / stepwise: min_w, max_w, step_w, min_h, max_h, step_h
+    size: [u32; 6],
+    reserved: [u32; 2],
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct V4l2FrmIvalEnum {
+    index: u32,
+    pixel_format: u32,
+    width: u32,
+    height: u32,
+    typ: u32,
+    // discrete: numerator, denominator / stepwise: min, max & step fractions
+    interval: [u32; 6],
+    reserved: [u32; 2],
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct DeviceCapabilities {
+    pub path: String,
+    pub name: String,
+    pub driver: String,
+    pub formats: Vec<FormatCapabilities>,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct FormatCapabilities {
+    pub fourcc: String,
+    /// Name to use as `input_format`, None when ffmpeg has no name for it
+    pub input_format: Option<String>,
+    pub description: String,
+    pub modes: Vec<CaptureMode>,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct CaptureMode {
+    pub width: u32,
+    pub height: u32,
+    pub framerates: Vec<f64>,
+}
+
+fn ioctl<T>(fd: i32, request: u32, arg: &mut T) -> bool {
+    // SAFETY: `arg` is the #[repr(C)] struct matching `request` in videodev2.h
+    unsafe { libc::ioctl(fd, request as libc::Ioctl, arg as *mut T) >= 0 }
+}
+
+fn c_string(bytes: &[u8]) -> String {
+    let end = bytes.iter().position(|b| *b == 0).unwrap_or(bytes.len());
+    String::from_utf8_lossy(&bytes[..end]).to_string()
+}
+
+fn fourcc(code: u32) -> String {
+    code.to_le_bytes()
+        .iter()
+        .map(|b| *b as char)
+        .collect::<String>()
+        .trim()
+        .to_string()
+}
+
+fn ffmpeg_input_format(fourcc: &str) -> Option<&'static str> {
+    match fourcc {
+        "MJPG" | "JPEG" => Some("mjpeg"),
+        "YUYV" => Some("yuyv422"),
+        "UYVY" => Some("uyvy422"),
+        "H264" => Some("h264"),
+        "NV12" => Some("nv12"),
+        "YU12" => Some("yuv420p"),
+        "RGB3" => Some("rgb24"),
+        "BGR3" => Some("bgr24"),
+        "GREY" => Some("gray"),
+        _ => None,
+    }
+}
+
+/// Lists every /dev/video* node able to capture video, with its formats, sizes & framerates
+pub fn list_devices() -> Vec<DeviceCapabilities> {
+    let mut paths: Vec<String> = match fs::read_dir("/dev") {
+        Ok(entries) => entries
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.file_name().to_string_lossy().to_string())
+            .filter(|name| name.starts_with("video"))
+            .map(|name| format!("/dev/{}", name))
+            .collect(),
+        Err(_) => return vec![],
+    };
+    paths.sort_by_key(|path| {
+        path.trim_start_matches("/dev/video")
+            .parse::<u32>()
+            .unwrap_or(u32::MAX)
+    });
+    // Metadata nodes (most UVC webcams expose one) can't capture & are skipped
+    paths
+        .iter()
+        .filter_map(|path| probe_device(path).ok())
+        .collect()
+}
+
+pub fn probe_device(path: &str) -> Result<DeviceCapabilities, io::Error> {
+    let device = fs::File::open(path)?;
+    let fd = device.as_raw_fd();
+
+    let mut capability = V4l2Capability::default();
+    if !ioctl(fd, VIDIOC_QUERYCAP, &mut capability) {
+        return Err(io::Error::last_os_error());
+    }
+    let caps = if capability.capabilities & V4L2_CAP_DEVICE_CAPS != 0 {
+        capability.device_caps
+    } else {
+        capability.capabilities
+    };
+    if caps & V4L2_CAP_VIDEO_CAPTURE == 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!("{} can't capture video", path),
+        ));
+    }
+
+    let mut formats = vec![];
+    let mut fmt = V4l2FmtDesc {
+        typ: V4L2_BUF_TYPE_VIDEO_CAPTURE,
+        ..Default::default()
+    };
+    while ioctl(fd, VIDIOC_ENUM_FMT, &mut fmt) {
+        let code = fourcc(fmt.pixelformat);
+        formats.push(FormatCapabilities {
+            input_format: ffmpeg_input_format(&code).map(String::from),
+            fourcc: code,
+            description: c_string(&fmt.description),
+            modes: enum_modes(fd, fmt.pixelformat),
+        });
+        fmt.index += 1;
+    }
+
+    Ok(DeviceCapabilities {
+        path: path.to_string(),
+        name: c_string(&capability.card),
+        driver: c_string(&capability.driver),
+        formats,
+    })
+}
+
+fn enum_modes(fd: i32, pixel_format: u32) -> Vec<CaptureMode> {
+    let mut modes = vec![];
+    let mut size = V4l2FrmSizeEnum {
+        pixel_format,
+        ..Default::default()
+    };
+    while ioctl(fd, VIDIOC_ENUM_FRAMESIZES, &mut size) {
+        let sizes = if size.typ == V4L2_FRMSIZE_TYPE_DISCRETE {
+            vec![(size.size[0], size.size[1])]
+        } else {
+            // Stepwise / continuous ranges : only the smallest & largest sizes are listed
+            vec![(size.size[0], size.size[3]), (size.size[1], size.size[4])]
+        };
+        for (width, height) in sizes {
+            modes.push(CaptureMode {
+                width,
+                height,
+                framerates: enum_framerates(fd, pixel_format, width, height),
+            });
+        }
+        if size.typ != V4L2_FRMSIZE_TYPE_DISCRETE {
+            break;
+        }
+        size.index += 1;
+    }
+    modes
+}
+
+fn enum_framerates(fd: i32, pixel_format: u32, width: u32, height: u32) -> Vec<f64> {
+    let mut framerates = vec![];
+    let mut interval = V4l2FrmIvalEnum {
+        pixel_format,
+        width,
+        height,
+        ..Default::default()
+    };
+    while ioctl(fd, VIDIOC_ENUM_FRAMEINTERVALS, &mut interval) {
+        let fractions = if interval.typ == V4L2_FRMIVAL_TYPE_DISCRETE {
+            vec![(interval.interval[0], interval.interval[1])]
+        } else {
+            // min interval is the max framerate & the other way around
+            vec![
+                (interval.interval[0], interval.interval[1]),
+                (interval.interval[2], interval.interval[3]),
+            ]
+        };
+        for (numerator, denominator) in fractions {
+            if numerator != 0 {
+                framerates.push(denominator as f64 / numerator as f64);
+            }
+        }
+        if interval.typ != V4L2_FRMIVAL_TYPE_DISCRETE {
+            break;
+        }
+        interval.index += 1;
+    }
+    framerates.sort_by(|a, b| b.total_cmp(a));
+    framerates.dedup();
+    framerates
+}
+
+/// Checks the camera's capture mode against what the device supports and switches
+/// to the closest supported one (with a warning) when it isn't.
+pub fn fit_capture_mode(
+    camera: &mut CameraConfig,
+    encoding: &mut EncodingConfig,
+    device: &DeviceCapabilities,
+) {
+    let usable: Vec<&FormatCapabilities> = device
+        .formats
+        .iter()
+        .filter(|format| format.input_format.is_some() && !format.modes.is_empty())
+        .collect();
+    let format = match usable
+        .iter()
+        .find(|format| format.input_format.as_deref() == Some(encoding.input_format.as_str()))
+    {
+        Some(format) => format,
+        None => {
+            // Prefer MJPEG, it's what gives the highest framerates on USB webcams
+            let Some(format) = usable
+                .iter()
+                .find(|format| format.input_format.as_deref() == Some("mjpeg"))
+                .or(usable.first())
+            else {
+                println!(
+                    "[{}] Warning: {} doesn't report any usable format",
+                    camera.id, device.path
+                );
+                return;
+            };
+            let input_format = format.input_format.clone().unwrap_or_default();
+            println!(
+                "[{}] Warning: {} doesn't support input_format {}, using {} instead",
+                camera.id, device.path, encoding.input_format, input_format
+            );
+            encoding.input_format = input_format;
+            format
+        }
+    };
+
+    let (width, height) = parse_resolution(&camera.resolution).unwrap_or((1280, 720));
+    let wanted_area = width as i64 * height as i64;
+    let Some(mode) = format
+        .modes
+        .iter()
+        .min_by_key(|mode| (mode.width as i64 * mode.height as i64 - wanted_area).abs())
+    else {
+        return;
+    };
+    if (mode.width, mode.height) != (width, height) {
+        let resolution = format!("{}x{}", mode.width, mode.height);
+        println!(
+            "[{}] Warning: {} doesn't support {} in {}, using {} instead",
+            camera.id, device.path, camera.resolution, encoding.input_format, resolution
+        );
+        camera.resolution = resolution;
+    }
+
+    if let Some(framerate) = camera.framerate
+        && !mode.framerates.is_empty()
+        && !mode
+            .framerates
+            .iter()
+            .any(|fps| (fps - framerate as f64).abs() < 0.5)
+    {
+        let closest = mode
+            .framerates
+            .iter()
+            .min_by(|a, b| {
+                (*a - framerate as f64)
+                    .abs()
+                    .total_cmp(&(*b - framerate as f64).abs())
+            })
+            .map(|fps| fps.round() as u32);
+        println!(
+            "[{}] Warning: {} doesn't support {} fps at {}, using {} instead",
+            camera.id,
+            device.path,
+            framerate,
+            camera.resolution,
+            closest.map_or("its default".to_string(), |fps| fps.to_string())
+        );
+        camera.framerate = closest;
+    }
+}
@@ -0,0 +1,163 @@
+use chrono::Local;
+use serde::Serialize;
+use std::{
+    collections::HashMap,
+    fs,
+    io::{BufRead, BufReader},
+    process::{Command, Stdio},
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::camera::{CameraConfig, source::redact_credentials, stream_dir};
+use crate::live::{LiveMessage, LiveSender};
+
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+// A run longer than this is considered healthy and resets the backoff
+const HEALTHY_RUN: Duration = Duration::from_secs(30);
+// After this many quick failures in a row the camera is reported as failed (we still keep retrying)
+const FAILED_AFTER: u32 = 5;
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum StreamState {
+    Starting,
+    Running,
+    Restarting,
+    Failed,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct StreamStatus {
+    pub state: StreamState,
+    pub since: String,
+    pub restarts: u32,
+    pub last_exit_code: Option<i32>,
+    pub last_error: Option<String>,
+}
+
+/// Status of every camera's ffmpeg process, keyed by camera id
+pub type StreamStatuses = Arc<Mutex<HashMap<String, StreamStatus>>>;
+
+fn set_state(statuses: &StreamStatuses, camera_id: &str, update: impl FnOnce(&mut StreamStatus)) {
+    let mut statuses = statuses.lock().unwrap();
+    let status = statuses
+        .entry(camera_id.to_string())
+        .or_insert_with(|| StreamStatus {
+            state: StreamState::Starting,
+            since: Local::now().to_rfc3339(),
+            restarts: 0,
+            last_exit_code: None,
+            last_error: None,
+        });
+    let previous = status.state;
+    update(status);
+    if status.state != previous {
+        status.since = Local::now().to_rfc3339();
+    }
+}
+
+/// Runs ffmpeg for a camera forever, restarting it with exponential backoff whenever it exits.
+pub fn start_ffmpeg_supervisor(
+    camera: CameraConfig,
+    args: Vec<String>,
+    statuses: StreamStatuses,
+    live: LiveSender,
+) {
+    set_state(&statuses, &camera.id, |_| {});
+    thread::spawn(move || {
+        let mut backoff = MIN_BACKOFF;
+        let mut quick_failures = 0;
+        loop {
+            println!(
+                "[{}] ffmpeg opening {}",
+                camera.id,
+                redact_credentials(&camera.device)
+            );
+            let started = Instant::now();
+            let (exit_code, last_error) = match run_ffmpeg(&camera, &args, &statuses) {
+                Ok(exit) => exit,
+                Err(err) => (None, Some(format!("Couldn't start ffmpeg: {}", err))),
+            };
+            println!(
+                "[{}] FFMPEG EXITED with code {}",
+                camera.id,
+                exit_code.map_or("none".to_string(), |code| code.to_string())
+            );
+
+            if started.elapsed() >= HEALTHY_RUN {
+                backoff = MIN_BACKOFF;
+                quick_failures = 0;
+            } else {
+                quick_failures += 1;
+            }
+            set_state(&statuses, &camera.id, |status| {
+                status.state = if quick_failures >= FAILED_AFTER {
+                    StreamState::Failed
+                } else {
+                    StreamState::Restarting
+                };
+                status.restarts += 1;
+                status.last_exit_code = exit_code;
+                if last_error.is_some() {
+                    status.last_error = last_error.clone();
+                }
+            });
+            // The next ffmpeg starts its segment numbering over, the archiver
+            // mustn't compare its names with the dead one's
+            let _ = fs::remove_file(format!("{}/stream.m3u8", stream_dir(&camera.id)));
+            live.send(LiveMessage::FfmpegRestart {
+                camera_id: camera.id.clone(),
+                exit_code,
+                error: last_error,
+                restart_in: backoff.as_secs(),
+            });
+
+            println!(
+                "[{}] restarting ffmpeg in {}s",
+                camera.id,
+                backoff.as_secs()
+            );
+            thread::sleep(backoff);
+            backoff = (backoff * 2).min(MAX_BACKOFF);
+        }
+    });
+}
+
+/// Spawns ffmpeg and blocks until it exits, forwarding its stderr to our log.
+/// Returns the exit code and the last line ffmpeg printed.
+fn run_ffmpeg(
+    camera: &CameraConfig,
+    args: &[String],
+    statuses: &StreamStatuses,
+) -> Result<(Option<i32>, Option<String>), std::io::Error> {
+    let mut child = Command::new("ffmpeg")
+        .args(args)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    set_state(statuses, &camera.id, |status| {
+        status.state = StreamState::Running;
+    });
+
+    let stderr_logger = child.stderr.take().map(|stderr| {
+        let camera_id = camera.id.clone();
+        thread::spawn(move || {
+            let mut last_line = None;
+            for line in BufReader::new(stderr).lines().map_while(Result::ok) {
+                // Also sent to the clients as the last error
+                let line = redact_credentials(&line);
+                println!("[{}] ffmpeg: {}", camera_id, line);
+                last_line = Some(line);
+            }
+            last_line
+        })
+    });
+
+    let exit_status = child.wait()?;
+    let last_line = stderr_logger.and_then(|handle| handle.join().ok().flatten());
+    Ok((exit_status.code(), last_line))
+}
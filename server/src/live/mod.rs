@@ -0,0 +1,84 @@
+use serde::Serialize;
+use tokio::sync::broadcast;
+
+// Messages kept for a slow client before it starts missing some
+const CHANNEL_CAPACITY: usize = 256;
+
+/// What happens on the cameras, pushed to the web UI as it happens
+#[derive(Serialize, Clone, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum LiveMessage {
+    EventStart {
+        camera_id: String,
+        start: String,
+        filename: String,
+    },
+    EventEnd {
+        camera_id: String,
+        /// Database id of the event, None if it couldn't be saved
+        id: Option<i64>,
+        start: String,
+        end: String,
+        filename: String,
+    },
+    /// The event's clip was generated, or failed to be
+    ClipReady {
+        camera_id: String,
+        filename: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        duration: Option<f64>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        error: Option<String>,
+    },
+    /// The detector receives frames from the camera's stream
+    CameraOnline { camera_id: String },
+    /// The detector lost the camera's stream
+    CameraOffline { camera_id: String },
+    FfmpegRestart {
+        camera_id: String,
+        exit_code: Option<i32>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        error: Option<String>,
+        /// Seconds before ffmpeg is started again
+        restart_in: u64,
+    },
+}
+
+impl LiveMessage {
+    /// SSE event name
+    pub fn kind(&self) -> &'static str {
+        match self {
+            LiveMessage::EventStart { .. } => "event_start",
+            LiveMessage::EventEnd { .. } => "event_end",
+            LiveMessage::ClipReady { .. } => "clip_ready",
+            LiveMessage::CameraOnline { .. } => "camera_online",
+            LiveMessage::CameraOffline { .. } => "camera_offline",
+            LiveMessage::FfmpegRestart { .. } => "ffmpeg_restart",
+        }
+    }
+}
+
+/// Broadcasts to every connected client, usable from the camera threads
+#[derive(Clone, Debug)]
+pub struct LiveSender(broadcast::Sender<LiveMessage>);
+
+impl LiveSender {
+    pub fn new() -> LiveSender {
+        LiveSender(broadcast::channel(CHANNEL_CAPACITY).0)
+    }
+
+    /// Doesn't block, messages are dropped when nobody listens
+    pub fn send(&self, message: LiveMessage) {
+        let _ = self.0.send(message);
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<LiveMessage> {
+        self.0.subscribe()
+    }
+}
+
+impl Default for LiveSender {
+    fn default() -> Self {
+        LiveSender::new()
+    }
+}
@@ -1,77 +1,250 @@
 use actix_cors::Cors;
 use actix_files::Files;
 use actix_web::{
-    get, middleware::from_fn, web::{self, Data}, App, HttpResponse, HttpServer, Responder
+    App, HttpResponse, HttpServer, Responder, get,
+    http::header::{self, HeaderName},
+    middleware::from_fn,
+    web::{self, Data},
 };
-use argon2::password_hash::{SaltString, rand_core::OsRng};
 use crossbeam_channel::unbounded;
 use serde::{Deserialize, Serialize};
 use std::{
     collections::HashMap,
-    fs,
-    process::Command,
-    sync::{
-        Mutex,
-    },
-    thread::{self},
-    time::{self, Instant},
+    env, fs,
+    sync::{Arc, Mutex},
 };
 
-use crate::routes::auth::{check_token_middleware, create_account, get_check_token, login};
+use crate::camera::CameraConfig;
+use crate::camera::encoding::EncodingConfig;
+use crate::camera::supervisor::StreamStatuses;
+use crate::live::LiveSender;
+use crate::movement_detector::DetectorState;
+use crate::movement_detector::events::DetectionEvent;
+use crate::movement_detector::objects::ObjectDetectionConfig;
+use crate::movement_detector::recording::RecordingConfig;
+use crate::movement_detector::settings::DetectionConfig;
+use crate::mqtt::MqttConfig;
+use crate::routes::api_keys::{create_api_key, get_api_keys, revoke_api_key};
+use crate::routes::auth::{
+    SecurityConfig, check_origin_middleware, check_token_middleware, create_account,
+    get_check_token, login, logout,
+};
+use crate::routes::cameras::{
+    get_cameras, get_detection, get_devices, get_snapshot, get_zones, set_detection, set_zones,
+};
+use crate::routes::events::{delete_event, get_event, get_events};
+use crate::routes::live::get_live;
+use crate::routes::login_limiter::LoginLimiter;
+use crate::routes::users::{
+    add_user, change_password, get_account, get_users, remove_user, set_user_password,
+};
+use crate::store::users::Role;
+use crate::store::{DATABASE_PATH, EventStore};
+pub mod camera;
+pub mod live;
 pub mod movement_detector;
+pub mod mp4;
+pub mod mqtt;
 pub mod routes;
+pub mod store;
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct Config {
     port: u16,
-    camera_path: String,
+    // Legacy single camera setting, migrated into `cameras` on startup
+    #[serde(default, skip_serializing)]
+    camera_path: Option<String>,
+    #[serde(default)]
+    encoding: EncodingConfig,
+    #[serde(default)]
+    cameras: Vec<CameraConfig>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    object_detection: Option<ObjectDetectionConfig>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    mqtt: Option<MqttConfig>,
+    #[serde(default)]
+    security: SecurityConfig,
+    // Legacy single user login, migrated into the users table on startup
+    #[serde(default, skip_serializing)]
     username: String,
+    #[serde(default, skip_serializing)]
     pass_hash: String,
-    salt: String,
 }
 
 #[derive(Debug)]
 struct AppState {
     config: Config,
-    tokens: HashMap<String, time::Instant>,
+    streams: StreamStatuses,
+    detectors: HashMap<String, Arc<DetectorState>>,
+    store: Arc<EventStore>,
+    live: LiveSender,
+    login_limiter: Arc<LoginLimiter>,
 }
 
 const CONFIG_PATH: &str = "./config/config.toml";
 
-
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
     println!("Loading configuration");
-    let config = load_config();
-    println!("starting ffmpeg hosting thread");
-    start_ffmpeg_webcam_streaming(config.camera_path.clone());
-    let (mov_detect_tx, mov_detect_rx) = unbounded::<bool>();
+    let mut config = load_config();
+    let args: Vec<String> = env::args().collect();
+    if args.len() >= 3 && args[1] == "replay" {
+        let camera = match args.get(3) {
+            Some(id) => config.cameras.iter().find(|camera| &camera.id == id),
+            None => config.cameras.first(),
+        };
+        let (settings, zones, recording) = match camera {
+            Some(camera) => (
+                camera.detection.clone(),
+                camera.zones.clone(),
+                camera.recording.clone(),
+            ),
+            None => (
+                DetectionConfig::default(),
+                vec![],
+                RecordingConfig::default(),
+            ),
+        };
+        if let Err(err) =
+            movement_detector::replay::replay_file(&args[2], settings, zones, recording.post_roll)
+        {
+            println!("ERROR: replay failed : {}", err);
+        }
+        return Ok(());
+    }
+    let _ = fs::remove_dir_all("./static/stream/");
+    fs::create_dir_all("./static")
+        .unwrap_or_else(|_| panic!("FATAL: Couldn't create ./static please check permissions"));
+    let store = EventStore::open(DATABASE_PATH).unwrap_or_else(|err| {
+        panic!(
+            "FATAL: Couldn't open the database {} : {}",
+            DATABASE_PATH, err
+        )
+    });
+    store
+        .sync_cameras(&config.cameras)
+        .unwrap_or_else(|err| panic!("FATAL: Couldn't save the cameras in the database : {}", err));
+    migrate_legacy_user(&mut config, &store);
+    let store = Arc::new(store);
+    store::sessions::start_session_cleaner(store.clone());
+    let live = LiveSender::new();
+    let streams: StreamStatuses = Arc::new(Mutex::new(HashMap::new()));
+    let mut detectors = HashMap::new();
+    for device in camera::probe::list_devices() {
+        let formats: Vec<String> = device
+            .formats
+            .iter()
+            .map(|format| format!("{} ({} modes)", format.fourcc, format.modes.len()))
+            .collect();
+        println!(
+            "found {} \"{}\" : {}",
+            device.path,
+            device.name,
+            formats.join(", ")
+        );
+    }
+    for camera in config.cameras.iter() {
+        println!("[{}] starting ffmpeg hosting thread", camera.id);
+        camera::start_ffmpeg_webcam_streaming(
+            camera.clone(),
+            camera.encoding_or(&config.encoding),
+            streams.clone(),
+            live.clone(),
+        );
+        let (mov_detect_tx, mov_detect_rx) = unbounded::<DetectionEvent>();
+
+        println!("[{}] starting camera detect thread", camera.id);
+        let detector = DetectorState::new(camera.detection.clone(), camera.zones.clone());
+        detectors.insert(camera.id.clone(), detector.clone());
+        movement_detector::start_movement_detect_thread(
+            camera.id.clone(),
+            detector,
+            config.object_detection.clone(),
+            mov_detect_tx,
+            live.clone(),
+        );
+        movement_detector::start_movement_logger(
+            camera.id.clone(),
+            camera.recording.clone(),
+            store.clone(),
+            mov_detect_rx,
+            live.clone(),
+        );
+    }
 
-    println!("starting camera detect thread");
-    movement_detector::start_movement_detect_thread(mov_detect_tx);
-    movement_detector::start_movement_logger(mov_detect_rx);
+    if let Some(mqtt) = config.mqtt.clone() {
+        println!("starting MQTT publisher");
+        mqtt::start_mqtt_publisher(
+            mqtt,
+            config.cameras.clone(),
+            store.clone(),
+            detectors.clone(),
+            &live,
+        );
+    }
 
     println!("starting web server");
     env_logger::init();
     let app_data = Data::new(Mutex::new(AppState {
         config: config.clone(),
-        tokens: HashMap::<String, Instant>::new(),
+        streams,
+        detectors,
+        store,
+        live,
+        login_limiter: Arc::new(LoginLimiter::default()),
     }));
+    let allowed_origins = config.security.allowed_origins.clone();
     HttpServer::new(move || {
+        // Only the configured origins, the web UI is served from the same origin through its proxy
+        let mut cors = Cors::default()
+            .allowed_methods(["GET", "POST", "DELETE"])
+            .allowed_headers([
+                header::AUTHORIZATION,
+                header::CONTENT_TYPE,
+                HeaderName::from_static("x-csrf-token"),
+            ])
+            .supports_credentials()
+            .max_age(3600);
+        for origin in allowed_origins.iter() {
+            cors = cors.allowed_origin(origin);
+        }
         let auth_protected_scope = web::scope("/protected")
             .wrap(from_fn(check_token_middleware))
             .service(Files::new("/stream", "./static/stream").show_files_listing())
             .service(Files::new("/clips", "./static/clips"))
-            .service(get_check_token);
+            .service(Files::new("/recordings", "./static/recordings").show_files_listing())
+            .service(get_check_token)
+            .service(get_cameras)
+            .service(get_events)
+            .service(get_event)
+            .service(delete_event)
+            .service(get_live)
+            .service(get_devices)
+            .service(get_zones)
+            .service(set_zones)
+            .service(get_detection)
+            .service(set_detection)
+            .service(get_snapshot)
+            .service(get_users)
+            .service(add_user)
+            .service(remove_user)
+            .service(set_user_password)
+            .service(get_account)
+            .service(change_password)
+            .service(get_api_keys)
+            .service(create_api_key)
+            .service(revoke_api_key);
 
         App::new()
             .app_data(app_data.clone())
             .service(create_account)
             .service(login)
+            .service(logout)
             .service(hello)
             .service(check_setup)
             .service(auth_protected_scope)
-            .wrap(Cors::permissive())
+            .wrap(from_fn(check_origin_middleware))
+            .wrap(cors)
     })
     .bind(("127.0.0.1", load_config().port))?
     .run()
@@ -85,33 +258,47 @@ async fn hello() -> impl Responder {
 
 #[get("/check_setup")]
 async fn check_setup(app_state: web::Data<Mutex<AppState>>) -> impl Responder {
-    let data = app_state.lock().unwrap();
-    if data.config.username == "" || data.config.pass_hash == "" {
-        HttpResponse::Ok().body("setup")
-    } else {
-        HttpResponse::Ok().body("")
+    let store = app_state.lock().unwrap().store.clone();
+    match store.has_users() {
+        Ok(false) => HttpResponse::Ok().body("setup"),
+        Ok(true) => HttpResponse::Ok().body(""),
+        Err(_) => HttpResponse::InternalServerError().body("Couldn't read the users"),
     }
 }
 
 fn load_config() -> Config {
-    let salt = SaltString::generate(&mut OsRng);
     let mut config = Config {
-        camera_path: "/dev/video0".to_string(),
+        camera_path: None,
+        encoding: EncodingConfig::default(),
+        cameras: vec![CameraConfig::from_device("cam0", "/dev/video0")],
+        object_detection: None,
+        mqtt: None,
+        security: SecurityConfig::default(),
         port: 8080,
         username: "".to_string(),
         pass_hash: "".to_string(),
-        salt: salt.to_string(),
     };
     match fs::read_to_string(CONFIG_PATH) {
         Ok(s) => match toml::from_str::<Config>(s.as_str()) {
             Ok(conf) => {
-                if conf.salt == "" {
-                    println!("No salt found : generating a new one");
-                    salt.to_string();
-                }
                 config = conf;
-
-            },
+                if config.cameras.is_empty() {
+                    let device = config
+                        .camera_path
+                        .take()
+                        .unwrap_or("/dev/video0".to_string());
+                    println!(
+                        "No [[cameras]] found : migrating camera_path {} to camera cam0",
+                        device
+                    );
+                    config
+                        .cameras
+                        .push(CameraConfig::from_device("cam0", device.as_str()));
+                    if write_config(&config).is_err() {
+                        println!("Warning: Couldn't save the migrated camera list to config.toml");
+                    }
+                }
+            }
             Err(_) => panic!("Couldn't parse config.toml please check the file."),
         },
         Err(_) => fs::write(
@@ -123,9 +310,50 @@ fn load_config() -> Config {
         ),
     }
 
+    camera::check_cameras(&config.cameras, &config.encoding);
+    if let Some(object_detection) = &config.object_detection
+        && let Err(err) = object_detection.check()
+    {
+        panic!("FATAL: [object_detection] {}", err);
+    }
+    if let Some(mqtt) = &config.mqtt
+        && let Err(err) = mqtt.check()
+    {
+        panic!("FATAL: [mqtt] {}", err);
+    }
+    if let Err(err) = config.security.check() {
+        panic!("FATAL: [security] {}", err);
+    }
     return config;
 }
 
+/// The login of config.toml becomes the first admin
+fn migrate_legacy_user(config: &mut Config, store: &EventStore) {
+    if config.username.is_empty() && config.pass_hash.is_empty() {
+        return;
+    }
+    let has_users = store
+        .has_users()
+        .unwrap_or_else(|err| panic!("FATAL: Couldn't read the users : {}", err));
+    if !has_users && !config.username.is_empty() && !config.pass_hash.is_empty() {
+        println!(
+            "migrating the login {} of config.toml to an admin user",
+            config.username
+        );
+        store
+            .create_user(&config.username, &config.pass_hash, Role::Admin)
+            .unwrap_or_else(|err| {
+                panic!("FATAL: Couldn't migrate the login to the users : {}", err)
+            });
+    }
+    config.username.clear();
+    config.pass_hash.clear();
+    // The legacy fields aren't written back
+    if write_config(config).is_err() {
+        println!("Warning: Couldn't remove the migrated login from config.toml");
+    }
+}
+
 pub enum WriteConfigError {
     FileSystemError,
     ParsingError,
@@ -141,51 +369,3 @@ pub fn write_config(config: &Config) -> Result<(), WriteConfigError> {
         Err(_) => return Err(WriteConfigError::ParsingError),
     }
 }
-
-fn start_ffmpeg_webcam_streaming(input: String) {
-    let _ = fs::remove_dir_all("./static/stream/");
-    match fs::create_dir_all("./static/stream") {
-        Ok(_) => println!("Warning: (re)created ./static/stream"),
-        Err(_) => {
-            fs::exists("./static/stream").expect("FATAL: Couldn't create ./static/stream please check permissions");
-        }
-    }
-
-    thread::spawn(move || {
-        println!("ffmpeg opening {}", input.as_str());
-        Command::new("ffmpeg")
-            .args([
-                "-f",
-                "v4l2",
-                "-input_format",
-                "mjpeg",
-                "-video_size",
-                "1280x720",
-                // "-framerate", "30",
-                "-vsync",
-                "0",
-                "-i",
-                input.as_str(),
-                "-c:v",
-                "libx264",
-                "-preset",
-                "ultrafast",
-                "-tune",
-                "zerolatency",
-                "-f",
-                "hls",
-                "-hls_flags",
-                "delete_segments+split_by_time", // +independent_segments
-                "-hls_segment_type",
-                "fmp4",
-                "-hls_list_size",
-                "5",
-                "-hls_time",
-                "4",
-                "static/stream/stream.m3u8",
-            ])
-            .output()
-            .expect("FATAL: Couldn't start FFMPEG");
-        println!("FFMPEG EXITED");
-    });
-}
@@ -0,0 +1,521 @@
+use chrono::{DateTime, Local, TimeDelta};
+use std::{
+    collections::{HashMap, VecDeque},
+    fs,
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+use tokio::sync::broadcast::error::TryRecvError;
+
+use crate::camera::{archive_dir, stream_dir};
+use crate::live::{LiveMessage, LiveSender};
+
+/// A finished fMP4 segment of the live stream, stored once in the camera's archive folder
+#[derive(Clone, Debug)]
+pub struct ArchivedSegment {
+    /// Increases with every segment, unlike ffmpeg's names which restart with the stream
+    pub seq: u64,
+    pub path: String,
+    /// init.mp4 the segment must be played with
+    pub init: String,
+    pub duration: f64,
+    /// Approximate wall clock time of the first frame
+    pub start: DateTime<Local>,
+}
+
+/// Video kept for a new recording, from before it started
+pub struct PreRoll {
+    /// Pass to `SegmentArchive::unpin` once the segments were used
+    pub pin: u64,
+    /// Sequence number of the first segment of the recording
+    pub first_seq: u64,
+    pub segments: Vec<ArchivedSegment>,
+    /// Seconds of video before now : the segments & the part of the one ffmpeg is writing
+    pub seconds: f64,
+}
+
+/// Index of the archived segments. Segments are kept for `pre_roll` seconds,
+/// or longer while a recording pinned them.
+pub struct SegmentArchive {
+    dir: String,
+    init: Option<(Vec<u8>, String)>,
+    init_count: u64,
+    segments: VecDeque<ArchivedSegment>,
+    /// Last segment taken from the playlist, even if it already left the archive
+    last_name: Option<String>,
+    /// #EXT-X-MEDIA-SEQUENCE of the last playlist read, goes back when ffmpeg restarts
+    last_media_sequence: Option<u64>,
+    next_seq: u64,
+    last_push: Option<Instant>,
+    pre_roll: f64,
+    /// Oldest segment each running recording still needs
+    pins: HashMap<u64, u64>,
+    next_pin: u64,
+}
+
+// Kept whatever the pre-roll, so recordings don't miss the segment that just finished
+const MIN_SEGMENTS: usize = 2;
+
+impl SegmentArchive {
+    fn new(dir: String, pre_roll: u32) -> SegmentArchive {
+        SegmentArchive {
+            dir,
+            init: None,
+            init_count: 0,
+            segments: VecDeque::new(),
+            last_name: None,
+            last_media_sequence: None,
+            next_seq: 0,
+            last_push: None,
+            pre_roll: pre_roll as f64,
+            pins: HashMap::new(),
+            next_pin: 0,
+        }
+    }
+
+    /// A new init.mp4 means ffmpeg restarted : it's archived next to the previous one
+    /// as older segments can't be played with it.
+    fn set_init(&mut self, data: Vec<u8>) -> Result<(), std::io::Error> {
+        if self.init.as_ref().is_some_and(|(known, _)| *known == data) {
+            return Ok(());
+        }
+        let path = format!("{}/init{}.mp4", self.dir, self.init_count);
+        fs::write(&path, &data)?;
+        self.init_count += 1;
+        self.init = Some((data, path));
+        self.last_name = None;
+        Ok(())
+    }
+
+    /// ffmpeg restarted : its next playlist starts over, maybe with the same segment names
+    fn playlist_restarted(&mut self) {
+        self.last_name = None;
+        self.last_media_sequence = None;
+    }
+
+    /// Segments of the playlist not taken yet, all of them if the last one taken left it
+    fn new_segments(&mut self, playlist: &str) -> Vec<(String, f64)> {
+        if let Some(sequence) = media_sequence(playlist) {
+            if self.last_media_sequence.is_some_and(|last| sequence < last) {
+                self.playlist_restarted();
+            }
+            self.last_media_sequence = Some(sequence);
+        }
+        let segments = playlist_segments(playlist);
+        let new = match &self.last_name {
+            Some(last) => match segments.iter().position(|(name, _)| name == last) {
+                Some(i) => i + 1,
+                None => 0,
+            },
+            None => 0,
+        };
+        segments.into_iter().skip(new).collect()
+    }
+
+    /// Hard links (or copies) a finished segment into the archive
+    fn archive(&mut self, source: &str, duration: f64) -> Result<(), std::io::Error> {
+        let Some((_, init)) = &self.init else {
+            return Ok(());
+        };
+        // The init number is in the name for `leftover_segments`
+        let path = format!(
+            "{}/{:010}-{}.m4s",
+            self.dir,
+            self.next_seq,
+            self.init_count - 1
+        );
+        if fs::hard_link(source, &path).is_err() {
+            fs::copy(source, &path)?;
+        }
+        self.segments.push_back(ArchivedSegment {
+            seq: self.next_seq,
+            path,
+            init: init.clone(),
+            duration,
+            start: Local::now() - TimeDelta::milliseconds((duration * 1000.0) as i64),
+        });
+        self.next_seq += 1;
+        self.last_push = Some(Instant::now());
+        self.evict();
+        Ok(())
+    }
+
+    /// Deletes the oldest segments while the newer ones cover the pre-roll and no recording needs them
+    fn evict(&mut self) {
+        let oldest_pin = self.pins.values().min().copied();
+        while self.segments.len() > MIN_SEGMENTS {
+            let newer: f64 = self.segments.iter().skip(1).map(|s| s.duration).sum();
+            let front = &self.segments[0];
+            if newer < self.pre_roll || oldest_pin.is_some_and(|pin| pin <= front.seq) {
+                break;
+            }
+            let segment = self.segments.pop_front().unwrap();
+            let _ = fs::remove_file(&segment.path);
+            let init_used = self
+                .init
+                .as_ref()
+                .is_some_and(|(_, init)| *init == segment.init)
+                || self.segments.iter().any(|s| s.init == segment.init);
+            if !init_used {
+                let _ = fs::remove_file(&segment.init);
+            }
+        }
+    }
+
+    /// Pins the newest segments covering the pre-roll. Older ones may still be archived
+    /// for other recordings or the continuous recording, they aren't part of it.
+    pub fn pin_pre_roll(&mut self) -> PreRoll {
+        let writing = self.last_push.map_or(0.0, |at| at.elapsed().as_secs_f64());
+        let mut seconds = writing;
+        let mut first = self.segments.len();
+        while first > 0 && seconds < self.pre_roll {
+            first -= 1;
+            seconds += self.segments[first].duration;
+        }
+        let segments: Vec<ArchivedSegment> = self.segments.range(first..).cloned().collect();
+        let first_seq = segments
+            .first()
+            .map_or(self.next_seq, |segment| segment.seq);
+        PreRoll {
+            pin: self.pin(first_seq),
+            first_seq,
+            segments,
+            seconds,
+        }
+    }
+
+    /// Segments archived after `seq`, all of them when None
+    pub fn segments_after(&self, seq: Option<u64>) -> Vec<ArchivedSegment> {
+        self.segments
+            .iter()
+            .filter(|segment| seq.is_none_or(|seq| segment.seq > seq))
+            .cloned()
+            .collect()
+    }
+
+    /// Keeps the segments from `seq` on until `unpin` is called with the returned id
+    pub fn pin(&mut self, seq: u64) -> u64 {
+        let id = self.next_pin;
+        self.next_pin += 1;
+        self.pins.insert(id, seq);
+        id
+    }
+
+    pub fn unpin(&mut self, id: u64) {
+        self.pins.remove(&id);
+    }
+
+    /// Sequence number of the next segment to be archived
+    pub fn next_seq(&self) -> u64 {
+        self.next_seq
+    }
+}
+
+/// `(segment file, duration)` of every segment listed in an HLS playlist
+fn playlist_segments(playlist: &str) -> Vec<(String, f64)> {
+    let mut segments = vec![];
+    let mut duration = None;
+    for line in playlist.lines().map(str::trim) {
+        if let Some(extinf) = line.strip_prefix("#EXTINF:") {
+            duration = extinf.split(',').next().and_then(|d| d.parse().ok());
+        } else if !line.is_empty()
+            && !line.starts_with('#')
+            && let Some(duration) = duration.take()
+        {
+            segments.push((line.to_string(), duration));
+        }
+    }
+    segments
+}
+
+/// `(seq, init number)` of an archived segment file name
+fn parse_segment_name(name: &str) -> Option<(u64, u64)> {
+    let (seq, init) = name.strip_suffix(".m4s")?.split_once('-')?;
+    Some((seq.parse().ok()?, init.parse().ok()?))
+}
+
+/// Segments a previous run left in an archive folder from `from_seq` on, in order.
+/// Their duration isn't known without the index and their start is only the time
+/// the file was written.
+pub fn leftover_segments(dir: &str, from_seq: u64) -> Vec<ArchivedSegment> {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return vec![];
+    };
+    let mut segments: Vec<ArchivedSegment> = entries
+        .filter_map(|entry| {
+            let entry = entry.ok()?;
+            let (seq, init) = parse_segment_name(entry.file_name().to_str()?)?;
+            let init = format!("{}/init{}.mp4", dir, init);
+            if seq < from_seq || !fs::exists(&init).unwrap_or(false) {
+                return None;
+            }
+            Some(ArchivedSegment {
+                seq,
+                path: entry.path().to_string_lossy().to_string(),
+                init,
+                duration: 0.0,
+                start: entry.metadata().ok()?.modified().ok()?.into(),
+            })
+        })
+        .collect();
+    segments.sort_by_key(|segment| segment.seq);
+    segments
+}
+
+fn media_sequence(playlist: &str) -> Option<u64> {
+    playlist
+        .lines()
+        .find_map(|line| line.trim().strip_prefix("#EXT-X-MEDIA-SEQUENCE:"))
+        .and_then(|sequence| sequence.trim().parse().ok())
+}
+
+/// Starts a thread archiving each segment of the camera's stream exactly once,
+/// as soon as it is listed in the playlist (so once ffmpeg finished writing it).
+pub fn start_segment_archiver(
+    camera_id: String,
+    pre_roll: u32,
+    live: &LiveSender,
+) -> Arc<Mutex<SegmentArchive>> {
+    let dir = archive_dir(&camera_id);
+    // The index only lives in memory, previous runs' segments can't be used.
+    // The continuous recording saves the ones it needs before, see `save_interrupted_recording`
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir)
+        .unwrap_or_else(|_| panic!("FATAL: Couldn't create {} please check permissions", dir));
+
+    let archive = Arc::new(Mutex::new(SegmentArchive::new(dir, pre_roll)));
+    let thread_archive = archive.clone();
+    let mut live = live.subscribe();
+    thread::spawn(move || {
+        let stream_dir = stream_dir(&camera_id);
+        loop {
+            thread::sleep(Duration::from_millis(500));
+            let mut restarted = false;
+            loop {
+                match live.try_recv() {
+                    Ok(LiveMessage::FfmpegRestart { camera_id: id, .. }) if id == camera_id => {
+                        restarted = true
+                    }
+                    Ok(_) | Err(TryRecvError::Lagged(_)) => {}
+                    Err(_) => break,
+                }
+            }
+            if restarted {
+                thread_archive.lock().unwrap().playlist_restarted();
+            }
+            let Ok(playlist) = fs::read_to_string(format!("{}/stream.m3u8", stream_dir)) else {
+                continue;
+            };
+            let Ok(init) = fs::read(format!("{}/init.mp4", stream_dir)) else {
+                continue;
+            };
+            let mut archive = thread_archive.lock().unwrap();
+            if let Err(err) = archive.set_init(init) {
+                println!("[{}] ERROR: Couldn't archive init.mp4 : {}", camera_id, err);
+                continue;
+            }
+            for (name, duration) in archive.new_segments(&playlist) {
+                let source = format!("{}/{}", stream_dir, name);
+                if let Err(err) = archive.archive(&source, duration) {
+                    // Already deleted by ffmpeg, happens when the thread was late
+                    println!("[{}] Warning: missed segment {} : {}", camera_id, name, err);
+                }
+                archive.last_name = Some(name);
+            }
+        }
+    });
+    archive
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PLAYLIST: &str = "#EXTM3U
+#EXT-X-VERSION:7
+#EXT-X-TARGETDURATION:2
+#EXT-X-MEDIA-SEQUENCE:4
+#EXT-X-MAP:URI=\"init.mp4\"
+#EXTINF:2.000000,
+stream4.m4s
+#EXTINF:1.500000,
+stream5.m4s
+";
+
+    fn archive(pre_roll: u32) -> SegmentArchive {
+        SegmentArchive::new("/nonexistent".to_string(), pre_roll)
+    }
+
+    /// Adds a segment without touching the file system
+    fn push(archive: &mut SegmentArchive, duration: f64) {
+        archive.segments.push_back(ArchivedSegment {
+            seq: archive.next_seq,
+            path: format!("/nonexistent/{}.m4s", archive.next_seq),
+            init: "/nonexistent/init0.mp4".to_string(),
+            duration,
+            start: Local::now(),
+        });
+        archive.next_seq += 1;
+        archive.evict();
+    }
+
+    fn seqs(archive: &SegmentArchive) -> Vec<u64> {
+        archive.segments.iter().map(|segment| segment.seq).collect()
+    }
+
+    #[test]
+    fn reads_playlist_segments() {
+        assert_eq!(
+            playlist_segments(PLAYLIST),
+            vec![
+                ("stream4.m4s".to_string(), 2.0),
+                ("stream5.m4s".to_string(), 1.5)
+            ]
+        );
+        assert_eq!(media_sequence(PLAYLIST), Some(4));
+        assert!(playlist_segments("#EXTM3U\n").is_empty());
+        // A segment without #EXTINF isn't taken
+        assert!(playlist_segments("#EXTM3U\nstream0.m4s\n").is_empty());
+    }
+
+    #[test]
+    fn takes_each_segment_once() {
+        let mut archive = archive(4);
+        assert_eq!(archive.new_segments(PLAYLIST).len(), 2);
+        archive.last_name = Some("stream5.m4s".to_string());
+        assert!(archive.new_segments(PLAYLIST).is_empty());
+
+        let next = PLAYLIST
+            .replace("SEQUENCE:4", "SEQUENCE:5")
+            .replace("#EXTINF:2.000000,\nstream4.m4s\n", "")
+            + "#EXTINF:2.000000,\nstream6.m4s\n";
+        assert_eq!(
+            archive.new_segments(&next),
+            vec![("stream6.m4s".to_string(), 2.0)]
+        );
+    }
+
+    #[test]
+    fn restarted_playlist_is_taken_again() {
+        let mut archive = archive(4);
+        archive.new_segments(PLAYLIST);
+        archive.last_name = Some("stream5.m4s".to_string());
+
+        // ffmpeg restarted with the same init, its numbering starts over
+        let restarted = "#EXTM3U
+#EXT-X-MEDIA-SEQUENCE:0
+#EXTINF:2.000000,
+stream4.m4s
+#EXTINF:2.000000,
+stream5.m4s
+";
+        assert_eq!(archive.new_segments(restarted).len(), 2);
+
+        // Same, noticed through the supervisor before the sequence went back
+        archive.last_name = Some("stream5.m4s".to_string());
+        archive.playlist_restarted();
+        assert_eq!(archive.new_segments(restarted).len(), 2);
+    }
+
+    #[test]
+    fn evicts_past_the_pre_roll() {
+        let mut archive = archive(5);
+        for _ in 0..6 {
+            push(&mut archive, 2.0);
+        }
+        // Without segment 3, the newer ones wouldn't cover the 5s pre-roll
+        assert_eq!(seqs(&archive), vec![3, 4, 5]);
+    }
+
+    #[test]
+    fn keeps_min_segments() {
+        let mut archive = archive(0);
+        for _ in 0..5 {
+            push(&mut archive, 2.0);
+        }
+        assert_eq!(seqs(&archive), vec![3, 4]);
+    }
+
+    #[test]
+    fn pre_roll_only_takes_the_newest_segments() {
+        let mut archive = archive(5);
+        // An earlier recording keeps everything archived
+        archive.pin(0);
+        for _ in 0..10 {
+            push(&mut archive, 2.0);
+        }
+        assert_eq!(archive.segments.len(), 10);
+
+        let pre_roll = archive.pin_pre_roll();
+        let seqs: Vec<u64> = pre_roll.segments.iter().map(|s| s.seq).collect();
+        assert_eq!(seqs, vec![7, 8, 9]);
+        assert_eq!(pre_roll.seconds, 6.0);
+        assert_eq!(pre_roll.first_seq, 7);
+        assert_eq!(archive.pins[&pre_roll.pin], 7);
+    }
+
+    #[test]
+    fn pre_roll_without_segments_pins_the_next_one() {
+        let mut empty = archive(5);
+        let pre_roll = empty.pin_pre_roll();
+        assert!(pre_roll.segments.is_empty());
+        assert_eq!(pre_roll.seconds, 0.0);
+        assert_eq!(empty.pins[&pre_roll.pin], 0);
+
+        let mut no_pre_roll = archive(0);
+        push(&mut no_pre_roll, 2.0);
+        let pre_roll = no_pre_roll.pin_pre_roll();
+        assert!(pre_roll.segments.is_empty());
+        assert_eq!(pre_roll.first_seq, 1);
+    }
+
+    #[test]
+    fn keeps_pinned_segments() {
+        let mut archive = archive(0);
+        push(&mut archive, 2.0);
+        let pin = archive.pin(0);
+        for _ in 0..4 {
+            push(&mut archive, 2.0);
+        }
+        assert_eq!(seqs(&archive), vec![0, 1, 2, 3, 4]);
+
+        archive.unpin(pin);
+        push(&mut archive, 2.0);
+        assert_eq!(seqs(&archive), vec![4, 5]);
+    }
+
+    #[test]
+    fn finds_the_segments_left_by_a_previous_run() {
+        let dir = std::env::temp_dir().join(format!("nephtys-archive-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        for name in [
+            "init0.mp4",
+            "init1.mp4",
+            "0000000002-0.m4s",
+            "0000000004-1.m4s",
+            "0000000003-0.m4s",
+            "0000000005-2.m4s",
+            "0000000001-0.m4s",
+            "stream.m3u8",
+        ] {
+            fs::write(dir.join(name), b"").unwrap();
+        }
+        let dir_path = dir.to_string_lossy().to_string();
+        let segments = leftover_segments(&dir_path, 2);
+        let _ = fs::remove_dir_all(&dir);
+
+        // Without its init, the 5th can't be played
+        let found: Vec<(u64, &str)> = segments
+            .iter()
+            .map(|segment| (segment.seq, segment.init.rsplit('/').next().unwrap()))
+            .collect();
+        assert_eq!(
+            found,
+            vec![(2, "init0.mp4"), (3, "init0.mp4"), (4, "init1.mp4")]
+        );
+        assert!(leftover_segments("/nonexistent", 0).is_empty());
+    }
+}
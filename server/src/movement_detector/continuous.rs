@@ -0,0 +1,270 @@
+use chrono::{DateTime, Days, Local, TimeDelta, Timelike};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs,
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant, SystemTime},
+};
+
+use crate::camera::{archive_dir, recordings_dir};
+use crate::movement_detector::archiver::{ArchivedSegment, SegmentArchive, leftover_segments};
+use crate::mp4::remux_fragments;
+use crate::store::EventStore;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum RecordingSplit {
+    /// One file per hour
+    #[default]
+    Hourly,
+    /// One file per day, starting at midnight
+    Daily,
+}
+
+/// `[cameras.recording.continuous]` section, used with `mode = "continuous"`
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct ContinuousConfig {
+    pub split: RecordingSplit,
+    /// Files older than this are deleted
+    pub max_age_hours: u32,
+    /// Disk budget of the continuous recordings, the oldest files are deleted first
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_megabytes: Option<u64>,
+}
+
+impl Default for ContinuousConfig {
+    fn default() -> Self {
+        ContinuousConfig {
+            split: RecordingSplit::Hourly,
+            max_age_hours: 96,
+            max_megabytes: None,
+        }
+    }
+}
+
+impl ContinuousConfig {
+    pub fn check(&self) -> Result<(), String> {
+        if self.max_age_hours == 0 {
+            return Err("max_age_hours must be at least 1".to_string());
+        }
+        if self.max_megabytes == Some(0) {
+            return Err("max_megabytes must be at least 1".to_string());
+        }
+        Ok(())
+    }
+}
+
+// How often the disk budget is checked between two files
+const RETENTION_INTERVAL: Duration = Duration::from_secs(60);
+// First segment of the file being recorded, in the archive folder so a restart can save it
+const FIRST_SEQ_FILE: &str = "continuous_first_seq";
+
+/// Start of the next hour or day
+fn next_split(now: DateTime<Local>, split: RecordingSplit) -> DateTime<Local> {
+    let next = match split {
+        RecordingSplit::Hourly => (now + TimeDelta::hours(1))
+            .with_minute(0)
+            .and_then(|t| t.with_second(0))
+            .and_then(|t| t.with_nanosecond(0)),
+        RecordingSplit::Daily => (now.date_naive() + Days::new(1))
+            .and_hms_opt(0, 0, 0)
+            .and_then(|t| t.and_local_timezone(Local).earliest()),
+    };
+    // Only fails around DST changes
+    next.unwrap_or(now + TimeDelta::hours(1))
+}
+
+/// Records the camera 24/7 : the archived segments are pinned until the end of the
+/// hour (or day) then remuxed into one file, next to the event clips which stay untouched.
+pub fn start_continuous_recording(
+    camera_id: String,
+    config: ContinuousConfig,
+    archive: Arc<Mutex<SegmentArchive>>,
+    store: Arc<EventStore>,
+) {
+    let dir = recordings_dir(&camera_id);
+    fs::create_dir_all(&dir)
+        .unwrap_or_else(|_| panic!("FATAL: Couldn't create {} please check permissions", dir));
+    thread::spawn(move || {
+        println!("[{}] continuous recording started", camera_id);
+        enforce_retention(&camera_id, &config, &store, 0);
+        // First segment of the current file, older ones may be kept for event clips
+        let (mut first_seq, mut pin) = {
+            let mut archive = archive.lock().unwrap();
+            let first_seq = archive.next_seq();
+            (first_seq, archive.pin(first_seq))
+        };
+        save_first_seq(&camera_id, first_seq);
+        let mut segments: Vec<ArchivedSegment> = vec![];
+        // Size of the segments waiting for the end of the period, they count in the budget
+        let mut pending_bytes: u64 = 0;
+        let mut split_at = next_split(Local::now(), config.split);
+        let mut retention_at = Instant::now() + RETENTION_INTERVAL;
+        loop {
+            thread::sleep(Duration::from_secs(1));
+            let mut archive_lock = archive.lock().unwrap();
+            let last_seq = segments.last().map(|segment| segment.seq);
+            let new = archive_lock.segments_after(last_seq);
+            for segment in new.into_iter().filter(|segment| segment.seq >= first_seq) {
+                pending_bytes += fs::metadata(&segment.path).map_or(0, |metadata| metadata.len());
+                segments.push(segment);
+            }
+            if Local::now() < split_at {
+                drop(archive_lock);
+                if Instant::now() >= retention_at {
+                    enforce_retention(&camera_id, &config, &store, pending_bytes);
+                    retention_at = Instant::now() + RETENTION_INTERVAL;
+                }
+                continue;
+            }
+            // The segment being written belongs to the next file
+            first_seq = archive_lock.next_seq();
+            let previous_pin = pin;
+            pin = archive_lock.pin(first_seq);
+            drop(archive_lock);
+
+            write_recordings(&camera_id, std::mem::take(&mut segments), &store);
+            pending_bytes = 0;
+            save_first_seq(&camera_id, first_seq);
+            archive.lock().unwrap().unpin(previous_pin);
+            enforce_retention(&camera_id, &config, &store, 0);
+            split_at = next_split(Local::now(), config.split);
+        }
+    });
+}
+
+fn save_first_seq(camera_id: &str, first_seq: u64) {
+    let path = format!("{}/{}", archive_dir(camera_id), FIRST_SEQ_FILE);
+    if let Err(err) = fs::write(&path, first_seq.to_string()) {
+        println!("[{}] ERROR: Couldn't write {} : {}", camera_id, path, err);
+    }
+}
+
+/// Saves the file the previous run was recording when it stopped, from the segments
+/// it left in the archive. Must run before `start_segment_archiver` clears them.
+pub fn save_interrupted_recording(camera_id: &str, store: &EventStore) {
+    let dir = archive_dir(camera_id);
+    let Some(first_seq) = fs::read_to_string(format!("{}/{}", dir, FIRST_SEQ_FILE))
+        .ok()
+        .and_then(|first_seq| first_seq.trim().parse().ok())
+    else {
+        return;
+    };
+    let segments = leftover_segments(&dir, first_seq);
+    if segments.is_empty() {
+        return;
+    }
+    println!(
+        "[{}] saving the {} segments recorded before the restart",
+        camera_id,
+        segments.len()
+    );
+    let recordings_dir = recordings_dir(camera_id);
+    if let Err(err) = fs::create_dir_all(&recordings_dir) {
+        println!(
+            "[{}] ERROR: Couldn't create {} : {}",
+            camera_id, recordings_dir, err
+        );
+        return;
+    }
+    write_recordings(camera_id, segments, store);
+}
+
+/// Remuxes the segments of a period, one file per init.mp4
+/// as segments from different ffmpeg runs can't be mixed.
+fn write_recordings(camera_id: &str, segments: Vec<ArchivedSegment>, store: &EventStore) {
+    let mut runs: Vec<Vec<ArchivedSegment>> = vec![];
+    for segment in segments {
+        match runs.last_mut() {
+            Some(run) if run[0].init == segment.init => run.push(segment),
+            _ => runs.push(vec![segment]),
+        }
+    }
+    for run in runs {
+        let output = format!(
+            "{}/{}.mp4",
+            recordings_dir(camera_id),
+            run[0].start.format("%Y-%m-%d_%H-%M-%S")
+        );
+        let paths: Vec<String> = run.iter().map(|segment| segment.path.clone()).collect();
+        match remux_fragments(&run[0].init, &paths, &output) {
+            Ok(summary) => {
+                println!(
+                    "[{}] saved recording {} ({:.0}s)",
+                    camera_id, output, summary.duration
+                );
+                if let Err(err) =
+                    store.add_recording(camera_id, &output, run[0].start, summary.duration)
+                {
+                    println!(
+                        "[{}] ERROR: Couldn't save recording {} : {}",
+                        camera_id, output, err
+                    );
+                }
+            }
+            Err(err) => println!(
+                "[{}] ERROR: Couldn't save recording {} : {}",
+                camera_id, output, err
+            ),
+        }
+    }
+}
+
+/// Deletes the recordings older than `max_age_hours`, then the oldest ones until they and the
+/// `pending_bytes` of the file being recorded fit in `max_megabytes`.
+/// Only the continuous recordings folder is looked at : event clips are never deleted here.
+fn enforce_retention(
+    camera_id: &str,
+    config: &ContinuousConfig,
+    store: &EventStore,
+    pending_bytes: u64,
+) {
+    let dir = recordings_dir(camera_id);
+    let Ok(entries) = fs::read_dir(&dir) else {
+        println!("[{}] ERROR: Couldn't read {}", camera_id, dir);
+        return;
+    };
+    let mut files: Vec<(String, SystemTime, u64)> = entries
+        .filter_map(|entry| {
+            let entry = entry.ok()?;
+            let name = entry.file_name().into_string().ok()?;
+            let metadata = entry.metadata().ok()?;
+            if !name.ends_with(".mp4") || !metadata.is_file() {
+                return None;
+            }
+            Some((name, metadata.modified().ok()?, metadata.len()))
+        })
+        .collect();
+    // Names are start times : sorting them puts the oldest first
+    files.sort();
+
+    let max_age = Duration::from_secs(config.max_age_hours as u64 * 3600);
+    let budget = config.max_megabytes.map(|mb| mb * 1024 * 1024);
+    let mut total: u64 = pending_bytes + files.iter().map(|(_, _, len)| len).sum::<u64>();
+    for (name, modified, len) in files {
+        let too_old = modified.elapsed().is_ok_and(|age| age > max_age);
+        let over_budget = budget.is_some_and(|budget| total > budget);
+        if !too_old && !over_budget {
+            continue;
+        }
+        let path = format!("{}/{}", dir, name);
+        match fs::remove_file(&path) {
+            Ok(_) => {
+                total -= len;
+                let _ = store.delete_recording(&path);
+                let reason = if too_old {
+                    "too old"
+                } else {
+                    "over the disk budget"
+                };
+                println!("[{}] removed recording {} ({})", camera_id, name, reason);
+            }
+            Err(err) => println!(
+                "[{}] ERROR: Couldn't remove recording {} : {}",
+                camera_id, name, err
+            ),
+        }
+    }
+}
@@ -0,0 +1,146 @@
+use opencv::{
+    core::{BORDER_CONSTANT, Point, Ptr, Scalar, Size, no_array},
+    imgproc::{
+        self, ADAPTIVE_THRESH_GAUSSIAN_C, MORPH_CLOSE, MORPH_OPEN, THRESH_BINARY, THRESH_BINARY_INV,
+    },
+    prelude::*,
+    video::{self, BackgroundSubtractorKNN, BackgroundSubtractorMOG2},
+};
+use serde::{Deserialize, Serialize};
+
+use crate::movement_detector::settings::DetectionConfig;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum DetectionAlgorithm {
+    /// Difference between two consecutive frames, cheap but misses slow movement
+    #[default]
+    FrameDiff,
+    /// Gaussian mixture background model, handles lighting changes & slow movement
+    Mog2,
+    /// K-nearest neighbours background model, usually better than MOG2 with few moving pixels
+    Knn,
+}
+
+/// Turns the preprocessed (grayscale & blurred) detection frames into a foreground mask:
+/// 255 where something moved, 0 elsewhere.
+pub trait MotionDetector {
+    /// Returns None while the detector doesn't have enough frames to compare yet
+    fn foreground_mask(
+        &mut self,
+        frame: &Mat,
+        settings: &DetectionConfig,
+    ) -> Result<Option<Mat>, opencv::Error>;
+}
+
+pub fn create_detector(
+    settings: &DetectionConfig,
+) -> Result<Box<dyn MotionDetector>, opencv::Error> {
+    Ok(match settings.algorithm {
+        DetectionAlgorithm::FrameDiff => Box::new(FrameDiffDetector { prev_frame: None }),
+        DetectionAlgorithm::Mog2 => Box::new(Mog2Detector {
+            subtractor: video::create_background_subtractor_mog2(
+                settings.history,
+                settings.background_threshold.unwrap_or(16.0),
+                true,
+            )?,
+        }),
+        DetectionAlgorithm::Knn => Box::new(KnnDetector {
+            subtractor: video::create_background_subtractor_knn(
+                settings.history,
+                settings.background_threshold.unwrap_or(400.0),
+                true,
+            )?,
+        }),
+    })
+}
+
+fn morphology(mask: &Mat, op: i32, size: i32, iterations: i32) -> Result<Mat, opencv::Error> {
+    let kernel = imgproc::get_structuring_element(
+        imgproc::MORPH_RECT,
+        Size::new(size, size),
+        Point::new(-1, -1),
+    )?;
+    let mut result = Mat::default();
+    imgproc::morphology_ex(
+        mask,
+        &mut result,
+        op,
+        &kernel,
+        Point::new(-1, -1),
+        iterations,
+        BORDER_CONSTANT,
+        Scalar::all(0.0),
+    )?;
+    Ok(result)
+}
+
+pub struct FrameDiffDetector {
+    prev_frame: Option<Mat>,
+}
+
+impl MotionDetector for FrameDiffDetector {
+    fn foreground_mask(
+        &mut self,
+        frame: &Mat,
+        settings: &DetectionConfig,
+    ) -> Result<Option<Mat>, opencv::Error> {
+        let Some(prev_frame) = self.prev_frame.replace(frame.try_clone()?) else {
+            return Ok(None);
+        };
+        let mut diff_frame = Mat::default();
+        opencv::core::subtract(frame, &prev_frame, &mut diff_frame, &no_array(), -1)?;
+        let mut mask_frame = Mat::default();
+        imgproc::adaptive_threshold(
+            &diff_frame,
+            &mut mask_frame,
+            255.0,
+            ADAPTIVE_THRESH_GAUSSIAN_C,
+            THRESH_BINARY_INV,
+            settings.threshold_block_size,
+            settings.threshold_c,
+        )?;
+        Ok(Some(morphology(&mask_frame, MORPH_CLOSE, 5, 2)?))
+    }
+}
+
+/// Background subtractors mark shadows as 127 : they are dropped and the
+/// remaining speckles removed before filling holes in moving shapes.
+fn clean_background_mask(fg_mask: &Mat) -> Result<Mat, opencv::Error> {
+    let mut binary = Mat::default();
+    imgproc::threshold(fg_mask, &mut binary, 200.0, 255.0, THRESH_BINARY)?;
+    let opened = morphology(&binary, MORPH_OPEN, 3, 1)?;
+    morphology(&opened, MORPH_CLOSE, 5, 2)
+}
+
+pub struct Mog2Detector {
+    subtractor: Ptr<BackgroundSubtractorMOG2>,
+}
+
+impl MotionDetector for Mog2Detector {
+    fn foreground_mask(
+        &mut self,
+        frame: &Mat,
+        _settings: &DetectionConfig,
+    ) -> Result<Option<Mat>, opencv::Error> {
+        let mut fg_mask = Mat::default();
+        self.subtractor.apply(frame, &mut fg_mask, -1.0)?;
+        Ok(Some(clean_background_mask(&fg_mask)?))
+    }
+}
+
+pub struct KnnDetector {
+    subtractor: Ptr<BackgroundSubtractorKNN>,
+}
+
+impl MotionDetector for KnnDetector {
+    fn foreground_mask(
+        &mut self,
+        frame: &Mat,
+        _settings: &DetectionConfig,
+    ) -> Result<Option<Mat>, opencv::Error> {
+        let mut fg_mask = Mat::default();
+        self.subtractor.apply(frame, &mut fg_mask, -1.0)?;
+        Ok(Some(clean_background_mask(&fg_mask)?))
+    }
+}
@@ -0,0 +1,45 @@
+use opencv::core::Rect;
+use serde::{Deserialize, Serialize};
+
+use crate::movement_detector::objects::DetectedObject;
+
+/// Moving area, as fractions of the frame (0.0 - 1.0) like the zones' points
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct BoundingBox {
+    pub x: f32,
+    pub y: f32,
+    pub width: f32,
+    pub height: f32,
+}
+
+impl BoundingBox {
+    /// Converts a box found on a `width` x `height` frame
+    pub fn from_rect(rect: &Rect, width: i32, height: i32) -> BoundingBox {
+        BoundingBox {
+            x: rect.x as f32 / width as f32,
+            y: rect.y as f32 / height as f32,
+            width: rect.width as f32 / width as f32,
+            height: rect.height as f32 / height as f32,
+        }
+    }
+
+    pub fn area(&self) -> f32 {
+        self.width * self.height
+    }
+}
+
+/// Sent by a detector thread to its logger each time movement is detected
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct DetectionEvent {
+    pub camera_id: String,
+    /// RFC 3339 time of the frame
+    pub timestamp: String,
+    /// Frames read by the detector thread since it started
+    pub frame_index: u64,
+    pub boxes: Vec<BoundingBox>,
+    /// Sum of the boxes' areas, fraction of the frame
+    pub moving_area: f32,
+    /// Objects found on the frame, None when no `[object_detection]` model is configured
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub labels: Option<Vec<DetectedObject>>,
+}
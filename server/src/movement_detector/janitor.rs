@@ -0,0 +1,261 @@
+use chrono::{DateTime, Local, TimeDelta};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+    sync::Arc,
+    thread,
+    time::Duration,
+};
+
+use crate::camera::clips_dir;
+use crate::store::EventStore;
+
+/// `[cameras.recording.retention]` section, limits of the event clips
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct ClipRetention {
+    /// Clips older than this are deleted
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_age_hours: Option<u32>,
+    /// Only the newest clips are kept
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_count: Option<usize>,
+    /// Disk budget of the clips, the oldest ones are deleted first
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_megabytes: Option<u64>,
+}
+
+impl Default for ClipRetention {
+    fn default() -> Self {
+        ClipRetention {
+            max_age_hours: Some(30 * 24),
+            max_count: None,
+            max_megabytes: None,
+        }
+    }
+}
+
+impl ClipRetention {
+    pub fn check(&self) -> Result<(), String> {
+        if self.max_age_hours == Some(0) {
+            return Err("retention max_age_hours must be at least 1".to_string());
+        }
+        if self.max_count == Some(0) {
+            return Err("retention max_count must be at least 1".to_string());
+        }
+        if self.max_megabytes == Some(0) {
+            return Err("retention max_megabytes must be at least 1".to_string());
+        }
+        Ok(())
+    }
+}
+
+const JANITOR_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+/// Everything stored for one clip : `<name>.mp4`, and the `<name>/` folder & `<name>.mkv`
+/// left by older versions
+struct ClipFiles {
+    paths: Vec<PathBuf>,
+    bytes: u64,
+    modified: Option<DateTime<Local>>,
+}
+
+/// A clip the janitor may delete, listed in the index or only found on disk
+struct Candidate {
+    /// Database id of the event, None for files missing from it
+    id: Option<i64>,
+    name: String,
+    time: DateTime<Local>,
+    bytes: u64,
+    paths: Vec<PathBuf>,
+}
+
+/// Starts a thread applying the retention policy to the camera's clips every few minutes
+pub fn start_clip_janitor(camera_id: String, retention: ClipRetention, store: Arc<EventStore>) {
+    thread::spawn(move || {
+        loop {
+            clean_clips(&camera_id, &retention, &store);
+            thread::sleep(JANITOR_INTERVAL);
+        }
+    });
+}
+
+fn clean_clips(camera_id: &str, retention: &ClipRetention, store: &EventStore) {
+    let dir = clips_dir(camera_id);
+    let mut files = match list_clip_files(&dir) {
+        Ok(files) => files,
+        Err(err) => {
+            println!("[{}] ERROR: Couldn't read {} : {}", camera_id, dir, err);
+            return;
+        }
+    };
+    let clips = match store.clips(camera_id) {
+        Ok(clips) => clips,
+        Err(err) => {
+            println!("[{}] ERROR: Couldn't list the clips : {}", camera_id, err);
+            return;
+        }
+    };
+
+    let mut candidates = vec![];
+    for clip in clips {
+        let clip_files = files.remove(&clip.filename);
+        // Still being generated
+        if !clip.finished {
+            continue;
+        }
+        let Ok(end) = DateTime::parse_from_rfc3339(&clip.end) else {
+            continue;
+        };
+        let clip_files = clip_files.unwrap_or(ClipFiles {
+            paths: vec![],
+            bytes: 0,
+            modified: None,
+        });
+        candidates.push(Candidate {
+            id: Some(clip.id),
+            name: clip.filename,
+            time: end.with_timezone(&Local),
+            bytes: clip_files.bytes,
+            paths: clip_files.paths,
+        });
+    }
+    // Clips missing from the index, from a previous run for example
+    for (name, clip) in files {
+        candidates.push(Candidate {
+            id: None,
+            name,
+            time: clip.modified.unwrap_or_else(Local::now),
+            bytes: clip.bytes,
+            paths: clip.paths,
+        });
+    }
+    candidates.sort_by_key(|candidate| candidate.time);
+
+    let oldest_kept = retention
+        .max_age_hours
+        .map(|hours| Local::now() - TimeDelta::hours(hours as i64));
+    let budget = retention.max_megabytes.map(|mb| mb * 1024 * 1024);
+    let mut count = candidates.len();
+    let mut total: u64 = candidates.iter().map(|candidate| candidate.bytes).sum();
+    let mut removed = vec![];
+    for candidate in candidates {
+        let reason = if oldest_kept.is_some_and(|oldest| candidate.time < oldest) {
+            "too old"
+        } else if retention.max_count.is_some_and(|max| count > max) {
+            "over max_count"
+        } else if budget.is_some_and(|budget| total > budget) {
+            "over the disk budget"
+        } else {
+            // Sorted oldest first : the next ones are kept too
+            break;
+        };
+        if let Err(err) = remove_clip_files(&candidate.paths) {
+            println!(
+                "[{}] ERROR: Couldn't remove clip {} : {}",
+                camera_id, candidate.name, err
+            );
+            continue;
+        }
+        println!(
+            "[{}] removed clip {} from {} ({}, {} bytes)",
+            camera_id,
+            candidate.name,
+            candidate.time.to_rfc3339(),
+            reason,
+            candidate.bytes
+        );
+        count -= 1;
+        total -= candidate.bytes;
+        removed.extend(candidate.id);
+    }
+
+    if removed.is_empty() {
+        return;
+    }
+    if let Err(err) = store.delete_events(&removed) {
+        println!(
+            "[{}] ERROR: Couldn't delete the removed events : {}",
+            camera_id, err
+        );
+    }
+}
+
+/// Deletes the files of a clip, for events removed through the API
+pub fn remove_clip(camera_id: &str, filename: &str) -> Result<(), std::io::Error> {
+    let dir = clips_dir(camera_id);
+    let paths: Vec<PathBuf> = [
+        format!("{}/{}.mp4", dir, filename),
+        format!("{}/{}.mkv", dir, filename),
+        format!("{}/{}", dir, filename),
+    ]
+    .into_iter()
+    .map(PathBuf::from)
+    .filter(|path| path.exists())
+    .collect();
+    remove_clip_files(&paths)
+}
+
+/// Clip files of the folder by clip name, index.json & unfinished clips aside
+fn list_clip_files(dir: &str) -> Result<HashMap<String, ClipFiles>, std::io::Error> {
+    let mut clips: HashMap<String, ClipFiles> = HashMap::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let Ok(file_name) = entry.file_name().into_string() else {
+            continue;
+        };
+        let metadata = entry.metadata()?;
+        let name = if metadata.is_dir() {
+            file_name.as_str()
+        } else if let Some(name) = file_name
+            .strip_suffix(".mp4")
+            .or_else(|| file_name.strip_suffix(".mkv"))
+        {
+            name
+        } else {
+            continue;
+        };
+        let bytes = if metadata.is_dir() {
+            dir_size(&entry.path())
+        } else {
+            metadata.len()
+        };
+        let modified = metadata.modified().ok().map(DateTime::<Local>::from);
+        let clip = clips.entry(name.to_string()).or_insert(ClipFiles {
+            paths: vec![],
+            bytes: 0,
+            modified: None,
+        });
+        clip.paths.push(entry.path());
+        clip.bytes += bytes;
+        clip.modified = clip.modified.max(modified);
+    }
+    Ok(clips)
+}
+
+fn dir_size(path: &Path) -> u64 {
+    let Ok(entries) = fs::read_dir(path) else {
+        return 0;
+    };
+    entries
+        .filter_map(|entry| entry.ok())
+        .map(|entry| match entry.metadata() {
+            Ok(metadata) if metadata.is_dir() => dir_size(&entry.path()),
+            Ok(metadata) => metadata.len(),
+            Err(_) => 0,
+        })
+        .sum()
+}
+
+fn remove_clip_files(paths: &[PathBuf]) -> Result<(), std::io::Error> {
+    for path in paths {
+        if path.is_dir() {
+            fs::remove_dir_all(path)?;
+        } else {
+            fs::remove_file(path)?;
+        }
+    }
+    Ok(())
+}
@@ -1,160 +1,182 @@
+use crate::camera::{clips_dir, stream_dir};
+use crate::live::{LiveMessage, LiveSender};
+use crate::movement_detector::archiver::{
+    ArchivedSegment, PreRoll, SegmentArchive, start_segment_archiver,
+};
+use crate::movement_detector::continuous::{
+    save_interrupted_recording, start_continuous_recording,
+};
+use crate::movement_detector::events::DetectionEvent;
+use crate::movement_detector::janitor::start_clip_janitor;
+use crate::movement_detector::objects::{
+    DetectedObject, ObjectDetectionConfig, ObjectDetector, merge_objects,
+};
+use crate::movement_detector::pipeline::DetectionPipeline;
+use crate::movement_detector::recording::{RecordingConfig, RecordingMode};
+use crate::movement_detector::settings::DetectionConfig;
+use crate::movement_detector::zones::DetectionZone;
+use crate::mp4::remux_fragments;
+use crate::store::EventStore;
 use chrono::Local;
 use crossbeam_channel::{Receiver, Sender, unbounded};
-use opencv::{
-    core::{BORDER_CONSTANT, BORDER_DEFAULT, Point, Point_, Size_, VecN, Vector, no_array},
-    imgproc::{
-        self, ADAPTIVE_THRESH_GAUSSIAN_C, CHAIN_APPROX_TC89_L1, COLOR_BGR2GRAY, INTER_LINEAR,
-        MORPH_CLOSE, RETR_EXTERNAL, THRESH_BINARY_INV, bounding_rect,
-    },
-    prelude::*,
-    videoio,
-};
+use opencv::{core::Vector, imgcodecs, prelude::*, videoio};
 use rand::distr::SampleString;
 use serde::{Deserialize, Serialize};
 use std::{
     fs::{self},
-    io::{self},
-    process::{Command, Stdio},
+    sync::{Arc, Mutex},
     thread,
-    time::{self, Duration},
+    time::{self, Duration, Instant},
 };
 
-pub fn start_movement_detect_thread(mov_detect_tx: Sender<bool>) {
+pub mod archiver;
+pub mod continuous;
+pub mod detectors;
+pub mod events;
+pub mod janitor;
+pub mod objects;
+pub mod pipeline;
+pub mod recording;
+pub mod replay;
+pub mod settings;
+pub mod zones;
+
+// Frames are downscaled to this size before looking for movement
+pub(crate) const DETECT_WIDTH: i32 = 640;
+pub(crate) const DETECT_HEIGHT: i32 = 360;
+
+/// Runtime state shared between a camera's detector thread and the API
+#[derive(Debug)]
+pub struct DetectorState {
+    pub settings: Mutex<DetectionConfig>,
+    pub zones: Mutex<Vec<DetectionZone>>,
+    /// Latest frame as JPEG, refreshed about once per detection window
+    pub snapshot: Mutex<Option<Vec<u8>>>,
+}
+
+impl DetectorState {
+    pub fn new(settings: DetectionConfig, zones: Vec<DetectionZone>) -> Arc<DetectorState> {
+        Arc::new(DetectorState {
+            settings: Mutex::new(settings),
+            zones: Mutex::new(zones),
+            snapshot: Mutex::new(None),
+        })
+    }
+}
+
+pub fn start_movement_detect_thread(
+    camera_id: String,
+    state: Arc<DetectorState>,
+    object_detection: Option<ObjectDetectionConfig>,
+    mov_detect_tx: Sender<DetectionEvent>,
+    live: LiveSender,
+) {
     thread::spawn(move || {
+        let playlist = format!("{}/stream.m3u8", stream_dir(&camera_id));
         // hardcoding a delay is bad
         // TODO: Detect when enough .m4s have been added to the stream folder and start once that is reached.
         thread::sleep(time::Duration::from_millis(15000));
-        loop {
-            if fs::exists("./static/stream/stream.m3u8").expect("Something went really wrong when trying to check on stream") {
-                break;
-            }
-        }
-        println!("movement detection thread starting...");
-        let mut cam =
-            videoio::VideoCapture::from_file("./static/stream/stream.m3u8", videoio::CAP_ANY)
-                .unwrap();
+        println!("[{}] movement detection thread starting...", camera_id);
+        let mut cam = open_stream(&playlist);
+        live.send(LiveMessage::CameraOnline {
+            camera_id: camera_id.clone(),
+        });
+        let mut failed_reads = 0;
         let mut frame = Mat::default(); // This array will store the web-cam data
-        let mut prev_frame = Mat::default();
-        let mut is_first_frame = true;
+        let settings = state.settings.lock().unwrap().clone();
+        let zones = state.zones.lock().unwrap().clone();
+        let mut pipeline = DetectionPipeline::new(settings, zones)
+            .expect("FATAL: Couldn't create the movement detector");
+        // Second stage, only runs on the frames where movement was detected
+        let mut object_detector =
+            object_detection.and_then(|config| match ObjectDetector::new(config) {
+                Ok(object_detector) => Some(object_detector),
+                Err(err) => {
+                    println!(
+                        "[{}] ERROR: Couldn't load the object detection model : {}",
+                        camera_id, err
+                    );
+                    None
+                }
+            });
+        let mut frame_index: u64 = 0;
 
-        let mut detection_count = 0;
-        let mut frame_count = 0;
         // Read the camera
-        // and display in the window
         loop {
             match cam.read(&mut frame) {
-                Ok(_) => {},
-                Err(_) => {
-                    continue;
-                }
-            }
-            match frame.size() {
-                Ok(_) => {}
-                Err(_) => {
+                Ok(true) => failed_reads = 0,
+                _ => {
+                    // ffmpeg probably got restarted by its supervisor : wait for the new stream
+                    failed_reads += 1;
+                    if failed_reads >= MAX_FAILED_READS {
+                        println!("[{}] lost the stream, reopening it", camera_id);
+                        live.send(LiveMessage::CameraOffline {
+                            camera_id: camera_id.clone(),
+                        });
+                        cam = open_stream(&playlist);
+                        live.send(LiveMessage::CameraOnline {
+                            camera_id: camera_id.clone(),
+                        });
+                        failed_reads = 0;
+                        if let Err(err) = pipeline.reset() {
+                            println!(
+                                "[{}] ERROR: Couldn't reset the detector : {}",
+                                camera_id, err
+                            );
+                        }
+                    } else {
+                        thread::sleep(Duration::from_millis(100));
+                    }
                     continue;
                 }
             }
-            let mut rescaled: Mat = Mat::default();
-            match imgproc::resize(
-                &frame,
-                &mut rescaled,
-                Size_ {
-                    width: 640,
-                    height: 360,
-                },
-                0.5,
-                0.5,
-                INTER_LINEAR,
-            ) {
-                Ok(_) => {}
-                Err(_) => continue,
-            }
-
-            let mut first_pass: Mat = Mat::default();
-            imgproc::cvt_color(&rescaled, &mut first_pass, COLOR_BGR2GRAY, 0).unwrap();
-            let mut final_frame: Mat = Mat::default();
-            imgproc::blur(
-                &first_pass,
-                &mut final_frame,
-                Size_ {
-                    width: 10,
-                    height: 10,
-                },
-                Point_ { x: -1, y: -1 },
-                BORDER_DEFAULT,
-            )
-            .unwrap();
-
-            frame_count += 1;
-
-            if is_first_frame {
-                is_first_frame = false;
-                prev_frame = final_frame;
+            if frame.empty() {
                 continue;
             }
 
-            let mut diff_frame = Mat::default();
-            opencv::core::subtract(&final_frame, &prev_frame, &mut diff_frame, &no_array(), -1)
-                .unwrap();
-            let mut mask_frame = Mat::default();
-            imgproc::adaptive_threshold(
-                &diff_frame,
-                &mut mask_frame,
-                255.0,
-                ADAPTIVE_THRESH_GAUSSIAN_C,
-                THRESH_BINARY_INV,
-                11,
-                3.0,
-            )
-            .unwrap();
-            let kernel = imgproc::get_structuring_element(
-                imgproc::MORPH_RECT,
-                Size_ {
-                    width: 5,
-                    height: 5,
-                },
-                Point { x: -1, y: -1 },
-            )
-            .unwrap();
-            let mut mask_frame_2 = Mat::default();
-            imgproc::morphology_ex(
-                &mask_frame,
-                &mut mask_frame_2,
-                MORPH_CLOSE,
-                &kernel,
-                Point { x: -1, y: -1 },
-                2,
-                BORDER_CONSTANT,
-                VecN::new(0.0, 0.0, 0.0, 0.0),
-            )
-            .unwrap();
-            let mut contours: Vector<Vector<Point_<i32>>> = Vector::new();
-            imgproc::find_contours(
-                &mask_frame_2,
-                &mut contours,
-                RETR_EXTERNAL,
-                CHAIN_APPROX_TC89_L1,
-                Point_ { x: 0, y: 0 },
-            )
-            .unwrap();
-
-            for countour in contours {
-                let bb = bounding_rect(&countour).unwrap();
-                if bb.area() > 250 {
-                    detection_count += 1;
-                    if detection_count > 10 {
-                        mov_detect_tx.send(true).unwrap();
-                        detection_count = 0;
-                    }
-                }
+            // Read once per frame so API changes apply without restarting the thread
+            let settings = state.settings.lock().unwrap().clone();
+            let zones = state.zones.lock().unwrap().clone();
+            if let Err(err) = pipeline.update_settings(&settings) {
+                println!(
+                    "[{}] ERROR: Couldn't apply detection settings : {}",
+                    camera_id, err
+                );
+            }
+            if let Err(err) = pipeline.update_zones(&zones) {
+                println!(
+                    "[{}] ERROR: Couldn't build the zones mask : {}",
+                    camera_id, err
+                );
             }
 
-            if frame_count >= 30 {
-                frame_count = 0;
-                detection_count = 0;
+            if frame_index.is_multiple_of(settings.window_frames as u64) {
+                update_snapshot(&state, &frame);
             }
+            frame_index += 1;
 
-            prev_frame = final_frame;
+            match pipeline.process(&frame) {
+                Ok(Some(boxes)) => {
+                    let labels = object_detector.as_mut().map(|object_detector| {
+                        object_detector.detect(&frame).unwrap_or_else(|err| {
+                            println!("[{}] ERROR: Object detection failed : {}", camera_id, err);
+                            vec![]
+                        })
+                    });
+                    mov_detect_tx
+                        .send(DetectionEvent {
+                            camera_id: camera_id.clone(),
+                            timestamp: Local::now().to_rfc3339(),
+                            frame_index,
+                            moving_area: boxes.iter().map(|bb| bb.area()).sum(),
+                            boxes,
+                            labels,
+                        })
+                        .unwrap()
+                }
+                Ok(None) => {}
+                Err(err) => println!("[{}] Skipping frame : {}", camera_id, err),
+            }
         }
     });
 
@@ -162,190 +184,367 @@ pub fn start_movement_detect_thread(mov_detect_tx: Sender<bool>) {
     println!("mov thread start");
 }
 
+const MAX_FAILED_READS: u32 = 50;
+
+fn update_snapshot(state: &DetectorState, frame: &Mat) {
+    let mut jpeg = Vector::<u8>::new();
+    if let Ok(true) = imgcodecs::imencode(".jpg", frame, &mut jpeg, &Vector::new()) {
+        *state.snapshot.lock().unwrap() = Some(jpeg.to_vec());
+    }
+}
+
+/// Blocks until the HLS playlist exists and can be opened
+fn open_stream(playlist: &str) -> videoio::VideoCapture {
+    loop {
+        if fs::exists(playlist).expect("Something went really wrong when trying to check on stream")
+            && let Ok(cam) = videoio::VideoCapture::from_file(playlist, videoio::CAP_ANY)
+            && cam.is_opened().unwrap_or(false)
+        {
+            return cam;
+        }
+        thread::sleep(Duration::from_secs(1));
+    }
+}
+
 #[derive(Serialize, Deserialize, Clone)]
-struct MovementEvent {
-    start: String,
-    end: String,
-    filename: String,
+pub struct MovementEvent {
+    /// Database id, None until the event is saved
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<i64>,
+    #[serde(default)]
+    pub camera_id: String,
+    pub start: String,
+    pub end: String,
+    pub filename: String,
+    /// Seconds of the clip before `start`
+    #[serde(default)]
+    pub pre_roll: f64,
+    /// Classes recognized during the event, best confidence first
+    #[serde(default)]
+    pub objects: Vec<DetectedObject>,
+    /// Every movement detection of the event, with where it happened
+    #[serde(default)]
+    pub detections: Vec<DetectionEvent>,
+    /// Length of the generated clip (s)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub duration: Option<f64>,
+    /// Why the clip couldn't be generated
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
 }
 
+/// Bumped when the index format changes, indexes without a version are from before versioning
+const INDEX_VERSION: u32 = 1;
+
 #[derive(Serialize, Deserialize)]
 struct MovementEventLogs {
+    #[serde(default)]
+    version: u32,
     events: Vec<MovementEvent>,
 }
 
-fn write_movements_logs(records: Vec<MovementEvent>) {
-    thread::spawn(move || {
-        let records_list = MovementEventLogs { events: records };
-        let contents = serde_json::to_string(&records_list);
-        match contents {
-            Ok(raw_json) => match fs::write("./static/clips/index.json", raw_json) {
-                Ok(_) => {
-                    println!("updated clips index")
+fn index_path(camera_id: &str) -> String {
+    format!("{}/index.json", clips_dir(camera_id))
+}
+
+/// Moves the events of the index.json written before the database into it,
+/// an unreadable index is put aside rather than overwritten
+fn import_index(camera_id: &str, store: &EventStore) {
+    let path = index_path(camera_id);
+    if store.has_events(camera_id).unwrap_or(true) {
+        return;
+    }
+    let Ok(raw_json) = fs::read_to_string(&path) else {
+        return;
+    };
+    match serde_json::from_str::<MovementEventLogs>(&raw_json) {
+        Ok(logs) if logs.version <= INDEX_VERSION => {
+            let count = logs.events.len();
+            for mut event in logs.events {
+                event.camera_id = camera_id.to_string();
+                if event.duration.is_none() && event.error.is_none() {
+                    event.error = Some("Interrupted by a server restart".to_string());
                 }
-                Err(_) => {
-                    println!("ERROR: Failed to update clips index")
+                if let Err(err) = store.insert_event(&event) {
+                    println!(
+                        "[{}] ERROR: Couldn't import event {} : {}",
+                        camera_id, event.filename, err
+                    );
                 }
-            },
-            Err(_) => {
-                println!("ERROR: Unexpected parsing error when updating clips index")
             }
+            println!("[{}] imported {} events from {}", camera_id, count, path);
+            let _ = fs::rename(&path, format!("{}.imported", path));
         }
-    });
+        Ok(logs) => {
+            println!(
+                "[{}] Warning: {} has version {}, newer than {}, keeping it as {}.bak",
+                camera_id, path, logs.version, INDEX_VERSION, path
+            );
+            let _ = fs::rename(&path, format!("{}.bak", path));
+        }
+        Err(err) => {
+            println!(
+                "[{}] Warning: couldn't parse {} ({}), keeping it as {}.bak",
+                camera_id, path, err, path
+            );
+            let _ = fs::rename(&path, format!("{}.bak", path));
+        }
+    }
 }
 
-pub fn start_movement_logger(mov_detect_rx: Receiver<bool>) {
-    match fs::create_dir_all("./static/clips") {
-        Ok(_) => println!("Warning: (re)created ./static/clips"),
+pub fn start_movement_logger(
+    camera_id: String,
+    recording: RecordingConfig,
+    store: Arc<EventStore>,
+    mov_detect_rx: Receiver<DetectionEvent>,
+    live: LiveSender,
+) {
+    let clips_dir = clips_dir(&camera_id);
+    match fs::create_dir_all(&clips_dir) {
+        Ok(_) => println!("Warning: (re)created {}", clips_dir),
         Err(_) => {
-            fs::exists("./static/clips")
-                .expect("FATAL: Couldn't create ./static/clips please check permissions");
+            fs::exists(&clips_dir).unwrap_or_else(|_| {
+                panic!(
+                    "FATAL: Couldn't create {} please check permissions",
+                    clips_dir
+                )
+            });
         }
     }
+    if recording.mode == RecordingMode::Continuous {
+        save_interrupted_recording(&camera_id, &store);
+    }
+    let archive = start_segment_archiver(camera_id.clone(), recording.pre_roll, &live);
+    if recording.mode == RecordingMode::Continuous {
+        start_continuous_recording(
+            camera_id.clone(),
+            recording.continuous.clone(),
+            archive.clone(),
+            store.clone(),
+        );
+    }
+    import_index(&camera_id, &store);
+    start_clip_janitor(
+        camera_id.clone(),
+        recording.retention.clone(),
+        store.clone(),
+    );
     thread::spawn(move || {
-        let mut records: Vec<MovementEvent> = vec![];
         let mut last_record_start = Local::now();
+        let mut pre_roll = 0.0;
         let mut in_event = false;
+        let mut event_objects: Vec<DetectedObject> = vec![];
+        let mut event_detections: Vec<DetectionEvent> = vec![];
         let (move_end_tx, move_end_rx) = unbounded();
         let mut filename = generate_name();
         loop {
-            match mov_detect_rx.recv_timeout(Duration::from_secs(5)) {
-                Ok(_) => {
+            match mov_detect_rx.recv_timeout(Duration::from_secs(recording.post_roll as u64)) {
+                Ok(detection) => {
                     let now = Local::now();
-                    println!("movement detected at {}", now.to_rfc3339());
+                    println!(
+                        "[{}] movement detected at {} ({} boxes, {:.1}% of the frame)",
+                        camera_id,
+                        detection.timestamp,
+                        detection.boxes.len(),
+                        detection.moving_area * 100.0
+                    );
+                    for object in detection.labels.iter().flatten() {
+                        println!(
+                            "[{}] {} ({:.0}%)",
+                            camera_id,
+                            object.label,
+                            object.confidence * 100.0
+                        );
+                    }
+                    merge_objects(
+                        &mut event_objects,
+                        detection.labels.as_deref().unwrap_or(&[]),
+                    );
+                    event_detections.push(detection);
                     if in_event {
                         continue;
                     }
                     in_event = true;
                     last_record_start = now;
-                    start_recording_clip(move_end_rx.clone(), filename.clone());
+                    let pre_roll_video = archive.lock().unwrap().pin_pre_roll();
+                    pre_roll = pre_roll_video.seconds;
+                    live.send(LiveMessage::EventStart {
+                        camera_id: camera_id.clone(),
+                        start: now.to_rfc3339(),
+                        filename: filename.clone(),
+                    });
+                    start_recording_clip(
+                        camera_id.clone(),
+                        archive.clone(),
+                        pre_roll_video,
+                        store.clone(),
+                        live.clone(),
+                        move_end_rx.clone(),
+                        filename.clone(),
+                    );
                 }
                 Err(_) => {
                     if !in_event {
                         continue;
                     }
-                    println!("5s without movement... stopping recroding");
+                    println!(
+                        "[{}] {}s without movement... stopping recording",
+                        camera_id, recording.post_roll
+                    );
                     in_event = false;
                     let now = Local::now();
-                    let _ = move_end_tx.send(()); // We end the record there
-                    records.push(MovementEvent {
+                    let event = MovementEvent {
+                        id: None,
+                        camera_id: camera_id.clone(),
                         start: last_record_start.to_rfc3339(),
                         end: now.to_rfc3339(),
                         filename: filename.clone(),
+                        pre_roll,
+                        objects: std::mem::take(&mut event_objects),
+                        detections: std::mem::take(&mut event_detections),
+                        duration: None,
+                        error: None,
+                    };
+                    let id = match store.insert_event(&event) {
+                        Ok(id) => Some(id),
+                        Err(err) => {
+                            println!(
+                                "[{}] ERROR: Couldn't save event {} : {}",
+                                camera_id, filename, err
+                            );
+                            None
+                        }
+                    };
+                    live.send(LiveMessage::EventEnd {
+                        camera_id: camera_id.clone(),
+                        id,
+                        start: event.start,
+                        end: event.end,
+                        filename: filename.clone(),
                     });
+                    // Saved first so the clip result has an event to go to
+                    let _ = move_end_tx.send(()); // We end the record there
                     filename = generate_name();
-                    write_movements_logs(records.clone());
                 }
             }
         }
     });
 }
 
-fn start_recording_clip(stop_signal: Receiver<()>, filename: String) {
+fn start_recording_clip(
+    camera_id: String,
+    archive: Arc<Mutex<SegmentArchive>>,
+    pre_roll: PreRoll,
+    store: Arc<EventStore>,
+    live: LiveSender,
+    stop_signal: Receiver<()>,
+    filename: String,
+) {
     thread::spawn(move || {
-        fs::create_dir(format!("./static/clips/{}/", filename)).expect("Couldn't record clip");
-        println!("Recording started");
+        println!("[{}] Recording started", camera_id);
+        // Starts with the pre-roll, the segments stay pinned in the archive until the clip is generated
+        let PreRoll {
+            pin,
+            first_seq,
+            mut segments,
+            ..
+        } = pre_roll;
         loop {
             match stop_signal.recv_timeout(Duration::from_millis(1000)) {
                 Ok(_) => {
-                    println!("Recording stopped");
-
-                    generate_mp4_from_chunks(filename);
-                    return;
-                }
-                Err(_) => {
-                    let recording_stream = format!("./static/clips/{}", filename);
-                    match fs::read_dir("./static/stream") {
-                        Ok(stream_files) => {
-                            for file in stream_files {
-                                match file {
-                                    Ok(file_entry) => {
-                                        let filename =
-                                            file_entry.file_name().into_string().unwrap();
-                                        fs::copy(
-                                            file_entry.path(),
-                                            format!("{}/{}", &recording_stream, &filename),
-                                        )
-                                        .expect("CANNOT COPY FILE");
-                                    }
-                                    Err(_) => {
-                                        println!("Skipping file in stream")
-                                    }
-                                }
-                            }
-                        }
-                        Err(_) => {
-                            println!("ERROR: Couldn't read ./static/stream")
+                    // The segment being written holds the end of the post-roll : wait for it
+                    let stopped_at = Instant::now();
+                    let recorded = segments.len();
+                    while stopped_at.elapsed() < MAX_LAST_SEGMENT_WAIT {
+                        add_new_segments(&archive, &mut segments, first_seq);
+                        if segments.len() > recorded {
+                            break;
                         }
+                        thread::sleep(Duration::from_millis(500));
                     }
+                    println!("[{}] Recording stopped", camera_id);
+
+                    let result = generate_clip(&camera_id, &filename, segments);
+                    archive.lock().unwrap().unpin(pin);
+                    if let Err(err) = store.set_clip_result(&filename, &result) {
+                        println!(
+                            "[{}] ERROR: Couldn't save clip {} : {}",
+                            camera_id, filename, err
+                        );
+                    }
+                    let (duration, error) = match result {
+                        Ok(duration) => (Some(duration), None),
+                        Err(err) => (None, Some(err)),
+                    };
+                    live.send(LiveMessage::ClipReady {
+                        camera_id,
+                        filename,
+                        duration,
+                        error,
+                    });
+                    return;
                 }
+                Err(_) => add_new_segments(&archive, &mut segments, first_seq),
             }
         }
     });
 }
 
+const MAX_LAST_SEGMENT_WAIT: Duration = Duration::from_secs(15);
+
+/// Segments archived since the last one of `segments`, older ones than `first_seq` aren't part of the clip
+fn add_new_segments(
+    archive: &Mutex<SegmentArchive>,
+    segments: &mut Vec<ArchivedSegment>,
+    first_seq: u64,
+) {
+    let last_seq = segments.last().map(|segment| segment.seq);
+    let new = archive.lock().unwrap().segments_after(last_seq);
+    segments.extend(new.into_iter().filter(|segment| segment.seq >= first_seq));
+}
+
 fn generate_name() -> String {
     rand::distr::Alphanumeric.sample_string(&mut rand::rng(), 32)
 }
 
-fn concat_mp4_fragments(filename: String) -> Result<(), io::Error> {
-    let mut output_file =
-        fs::File::create_new(format!("./static/clips/{}/concat.m4s", filename))?;
-    let mut init_file = fs::File::open(format!("./static/clips/{}/init.mp4", filename))?;
-    io::copy(&mut init_file, &mut output_file)?;
-    match fs::read_dir(format!("./static/clips/{}", filename)) {
-        Ok(stream_files) => {
-            let mut stream_files_sorted = stream_files
-                .map(|res| res.map(|e| e.file_name()))
-                .into_iter()
-                .collect::<Result<Vec<_>, io::Error>>()?;
-
-            stream_files_sorted.sort();
-            for file_path_ostr in stream_files_sorted {
-                let file_name_str = &file_path_ostr.into_string().unwrap();
-                let file_path_str = format!("./static/clips/{}/{}", filename, file_name_str);
-                let file_path = std::path::Path::new(&file_path_str);
-
-                match fs::File::open(format!("./static/clips/{}/{}", filename, file_name_str)) {
-                    Ok(mut file_entry) => {
-                        let filename = file_path.file_name().unwrap().to_str().unwrap();
-                        if filename.ends_with(".m4s") && filename != "concat.m4s" {
-                            io::copy(&mut file_entry, &mut output_file).unwrap();
-                        }
-                    }
-                    Err(err) => {
-                        println!("OPENFILERROR: {}", err);
-                        println!("Skipping file {} in stream", file_name_str)
-                    }
-                }
-            }
-
-            return Ok(())
+/// Remuxes the recorded segments into `<clips_dir>/<filename>.mp4`, returns its duration
+fn generate_clip(
+    camera_id: &str,
+    filename: &str,
+    mut segments: Vec<ArchivedSegment>,
+) -> Result<f64, String> {
+    segments.sort_by_key(|segment| segment.seq);
+    let Some(init) = segments.last().map(|segment| segment.init.clone()) else {
+        return Err("No segment was recorded".to_string());
+    };
+    // Segments from before an ffmpeg restart can't be played with the current init.mp4
+    let paths: Vec<String> = segments
+        .iter()
+        .filter(|segment| segment.init == init)
+        .map(|segment| segment.path.clone())
+        .collect();
+    if paths.len() < segments.len() {
+        println!(
+            "[{}] Warning: skipping {} segments recorded before ffmpeg restarted",
+            camera_id,
+            segments.len() - paths.len()
+        );
+    }
+    let output = format!("{}/{}.mp4", clips_dir(camera_id), filename);
+    match remux_fragments(&init, &paths, &output) {
+        Ok(summary) => {
+            println!(
+                "[{}] clip {} ready ({:.1}s, {} samples)",
+                camera_id, filename, summary.duration, summary.samples
+            );
+            Ok(summary.duration)
         }
-        Err(_) => {
-            println!("ERROR: Couldn't read ./static/stream");
-            Err(io::Error::new(io::ErrorKind::NotFound, "Couldn't read ./static/stream"))
+        Err(err) => {
+            println!(
+                "[{}] ERROR: Couldn't generate clip {} : {}",
+                camera_id, filename, err
+            );
+            Err(err.to_string())
         }
     }
 }
-
-fn generate_mp4_from_chunks(filename: String) {
-    thread::spawn(move || {
-        match concat_mp4_fragments(filename.clone()) {
-            Ok(_) => {},
-            Err(_) => {println!("WARNING: Couldn't generate MP4 of clip"); return}
-        }
-        let _ffmpeg_proc: Result<std::process::Child, std::io::Error> = Command::new("ffmpeg")
-            .args([
-                "-v",
-                "0",
-                "-i",
-                format!("./static/clips/{}/concat.m4s", filename).as_str(),
-                "-c:v",
-                "copy",
-                format!("./static/clips/{}.mkv", filename).as_str(),
-            ])
-            .stdout(Stdio::piped())
-            .spawn();
-    });
-}
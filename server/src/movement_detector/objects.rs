@@ -0,0 +1,258 @@
+use opencv::{
+    core::{CV_32F, Scalar, Size, StsError},
+    dnn::{self, DNN_BACKEND_OPENCV, DNN_TARGET_CPU, Net},
+    prelude::*,
+};
+use serde::{Deserialize, Serialize};
+use std::fs;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ModelFormat {
+    /// SSD style output `[1, 1, N, 7]` (MobileNet-SSD ...)
+    #[default]
+    Ssd,
+    /// YOLO style output, v5 `[1, N, 5 + classes]` or v8 `[1, 4 + classes, N]`
+    Yolo,
+}
+
+fn default_confidence() -> f32 {
+    0.5
+}
+
+/// Top level `[object_detection]` section : a DNN model run on the CPU on the frames
+/// that triggered a movement detection, to tell people from cats.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ObjectDetectionConfig {
+    /// Model file (.onnx, .caffemodel, .pb ...)
+    pub model: String,
+    /// Network description, only needed by caffe (.prototxt) & tensorflow (.pbtxt) models
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub config: Option<String>,
+    #[serde(default)]
+    pub format: ModelFormat,
+    /// Text file with one class name per line, in the model's class id order
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub labels: Option<String>,
+    /// Side of the square input the model expects (px), defaults to 300 for ssd & 640 for yolo
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub input_size: Option<i32>,
+    /// Detections scoring less than this (0.0 - 1.0) are dropped
+    #[serde(default = "default_confidence")]
+    pub confidence: f32,
+}
+
+impl ObjectDetectionConfig {
+    /// Checks the values & files before the detector threads start, returns a human readable error otherwise
+    pub fn check(&self) -> Result<(), String> {
+        for path in [
+            Some(&self.model),
+            self.config.as_ref(),
+            self.labels.as_ref(),
+        ]
+        .into_iter()
+        .flatten()
+        {
+            if !fs::exists(path).unwrap_or(false) {
+                return Err(format!("{} doesn't exist", path));
+            }
+        }
+        if !(self.confidence > 0.0 && self.confidence <= 1.0) {
+            return Err("confidence must be between 0.0 and 1.0".to_string());
+        }
+        if let Some(size) = self.input_size
+            && size < 32
+        {
+            return Err("input_size must be at least 32".to_string());
+        }
+        Ok(())
+    }
+}
+
+/// A class found by the model and its best score
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct DetectedObject {
+    pub label: String,
+    pub confidence: f32,
+}
+
+/// Adds `found` to `objects`, keeping the highest confidence per label
+pub fn merge_objects(objects: &mut Vec<DetectedObject>, found: &[DetectedObject]) {
+    for object in found {
+        match objects.iter_mut().find(|known| known.label == object.label) {
+            Some(known) => known.confidence = known.confidence.max(object.confidence),
+            None => objects.push(object.clone()),
+        }
+    }
+    objects.sort_by(|a, b| b.confidence.total_cmp(&a.confidence));
+}
+
+pub struct ObjectDetector {
+    net: Net,
+    labels: Vec<String>,
+    config: ObjectDetectionConfig,
+}
+
+impl ObjectDetector {
+    pub fn new(config: ObjectDetectionConfig) -> Result<ObjectDetector, String> {
+        let mut net = dnn::read_net(&config.model, config.config.as_deref().unwrap_or(""), "")
+            .map_err(|err| err.to_string())?;
+        if net.empty().unwrap_or(true) {
+            return Err(format!("{} didn't load any network", config.model));
+        }
+        net.set_preferable_backend(DNN_BACKEND_OPENCV)
+            .and_then(|_| net.set_preferable_target(DNN_TARGET_CPU))
+            .map_err(|err| err.to_string())?;
+        let labels = match &config.labels {
+            Some(path) => fs::read_to_string(path)
+                .map_err(|err| format!("Couldn't read {} : {}", path, err))?
+                .lines()
+                .map(|line| line.trim().to_string())
+                .collect(),
+            None => vec![],
+        };
+        Ok(ObjectDetector {
+            net,
+            labels,
+            config,
+        })
+    }
+
+    /// Runs the model on a BGR frame, returns each class found once with its best score
+    pub fn detect(&mut self, frame: &Mat) -> Result<Vec<DetectedObject>, opencv::Error> {
+        let (size, scale, mean, swap_rb) = match self.config.format {
+            ModelFormat::Ssd => (300, 1.0 / 127.5, 127.5, false),
+            ModelFormat::Yolo => (640, 1.0 / 255.0, 0.0, true),
+        };
+        let size = self.config.input_size.unwrap_or(size);
+        let blob = dnn::blob_from_image(
+            frame,
+            scale,
+            Size::new(size, size),
+            Scalar::all(mean),
+            swap_rb,
+            false,
+            CV_32F,
+        )?;
+        self.net.set_input(&blob, "", 1.0, Scalar::default())?;
+        let output = self.net.forward_single("")?;
+
+        let scores = match self.config.format {
+            ModelFormat::Ssd => ssd_scores(&output)?,
+            ModelFormat::Yolo => yolo_scores(&output)?,
+        };
+        let mut objects = vec![];
+        for (class_id, confidence) in scores {
+            if confidence < self.config.confidence {
+                continue;
+            }
+            let label = self
+                .labels
+                .get(class_id)
+                .cloned()
+                .unwrap_or_else(|| format!("class {}", class_id));
+            merge_objects(&mut objects, &[DetectedObject { label, confidence }]);
+        }
+        Ok(objects)
+    }
+}
+
+/// `[1, 1, N, 7]` rows of `[image, class, confidence, x1, y1, x2, y2]`
+fn ssd_scores(output: &Mat) -> Result<Vec<(usize, f32)>, opencv::Error> {
+    Ok(output
+        .data_typed::<f32>()?
+        .chunks_exact(7)
+        .map(|row| (row[1] as usize, row[2]))
+        .collect())
+}
+
+/// Best class of every candidate box, boxes themselves aren't needed to tag an event
+fn yolo_scores(output: &Mat) -> Result<Vec<(usize, f32)>, opencv::Error> {
+    let shape = output.mat_size();
+    if shape.len() != 3 {
+        return Err(opencv::Error::new(
+            StsError,
+            format!("unexpected YOLO output shape {:?}", &*shape),
+        ));
+    }
+    let (rows, cols) = (shape[1] as usize, shape[2] as usize);
+    let data = output.data_typed::<f32>()?;
+    parse_yolo_output(data, rows, cols).map_err(|err| opencv::Error::new(StsError, err))
+}
+
+/// Best (class id, score) of each box of a YOLO output of `rows` x `cols`
+fn parse_yolo_output(data: &[f32], rows: usize, cols: usize) -> Result<Vec<(usize, f32)>, String> {
+    if data.len() < rows * cols {
+        return Err(format!(
+            "YOLO output has {} values for a {}x{} shape",
+            data.len(),
+            rows,
+            cols
+        ));
+    }
+    let mut scores = vec![];
+    if rows > cols {
+        // v5 : one row per box, [cx, cy, w, h, objectness, class scores...]
+        if cols < 6 {
+            return Err(format!(
+                "YOLOv5 output rows have {} values, expected at least 6",
+                cols
+            ));
+        }
+        for row in data[..rows * cols].chunks_exact(cols) {
+            if let Some((class_id, score)) = best_class(row[5..].iter().copied()) {
+                scores.push((class_id, score * row[4]));
+            }
+        }
+    } else {
+        // v8 : one column per box, [cx, cy, w, h, class scores...]
+        if rows < 5 {
+            return Err(format!(
+                "YOLOv8 output has {} rows, expected at least 5",
+                rows
+            ));
+        }
+        for i in 0..cols {
+            let column = (4..rows).map(|row| data[row * cols + i]);
+            if let Some(best) = best_class(column) {
+                scores.push(best);
+            }
+        }
+    }
+    Ok(scores)
+}
+
+fn best_class(scores: impl Iterator<Item = f32>) -> Option<(usize, f32)> {
+    scores.enumerate().max_by(|(_, a), (_, b)| a.total_cmp(b))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rejects_outputs_too_small_for_yolo() {
+        // v5 layout with 5 columns : no class scores
+        assert!(parse_yolo_output(&[0.0; 40], 8, 5).is_err());
+        // v8 layout with 4 rows : no class scores
+        assert!(parse_yolo_output(&[0.0; 32], 4, 8).is_err());
+        // Fewer values than the shape
+        assert!(parse_yolo_output(&[0.0; 10], 8, 7).is_err());
+    }
+
+    #[test]
+    fn reads_v5_and_v8_layouts() {
+        // v5 : 8 boxes of [cx, cy, w, h, objectness, class 0, class 1]
+        let mut v5 = vec![0.0; 8 * 7];
+        v5[4] = 0.5;
+        v5[6] = 0.8;
+        let scores = parse_yolo_output(&v5, 8, 7).unwrap();
+        assert_eq!(scores[0], (1, 0.4));
+
+        // v8 : 6 rows ([cx, cy, w, h, class 0, class 1]) of 8 boxes
+        let mut v8 = vec![0.0; 6 * 8];
+        v8[4 * 8 + 2] = 0.9;
+        let scores = parse_yolo_output(&v8, 6, 8).unwrap();
+        assert_eq!(scores[2], (0, 0.9));
+    }
+}
@@ -0,0 +1,143 @@
+use opencv::{
+    core::{BORDER_DEFAULT, Point, Size, Vector, no_array},
+    imgproc::{self, CHAIN_APPROX_TC89_L1, COLOR_BGR2GRAY, INTER_LINEAR, RETR_EXTERNAL},
+    prelude::*,
+};
+
+use crate::movement_detector::{
+    DETECT_HEIGHT, DETECT_WIDTH,
+    detectors::{MotionDetector, create_detector},
+    events::BoundingBox,
+    settings::DetectionConfig,
+    zones::{DetectionZone, build_zone_mask},
+};
+
+/// Everything between a decoded frame and "movement detected" :
+/// downscaling, the selected MotionDetector, zones & the per window hit counting.
+/// Used by the live detector threads and by the replay harness.
+pub struct DetectionPipeline {
+    detector: Box<dyn MotionDetector>,
+    settings: DetectionConfig,
+    zones: Vec<DetectionZone>,
+    zone_mask: Option<Mat>,
+    detection_count: u32,
+    frame_count: u32,
+}
+
+impl DetectionPipeline {
+    pub fn new(
+        settings: DetectionConfig,
+        zones: Vec<DetectionZone>,
+    ) -> Result<DetectionPipeline, opencv::Error> {
+        Ok(DetectionPipeline {
+            detector: create_detector(&settings)?,
+            zone_mask: build_zone_mask(&zones, DETECT_WIDTH, DETECT_HEIGHT)?,
+            settings,
+            zones,
+            detection_count: 0,
+            frame_count: 0,
+        })
+    }
+
+    /// Applies new settings, the detector is only rebuilt when its own parameters changed
+    pub fn update_settings(&mut self, settings: &DetectionConfig) -> Result<(), opencv::Error> {
+        if *settings == self.settings {
+            return Ok(());
+        }
+        if settings.algorithm != self.settings.algorithm
+            || settings.history != self.settings.history
+            || settings.background_threshold != self.settings.background_threshold
+        {
+            self.detector = create_detector(settings)?;
+        }
+        self.settings = settings.clone();
+        Ok(())
+    }
+
+    pub fn update_zones(&mut self, zones: &[DetectionZone]) -> Result<(), opencv::Error> {
+        if zones == self.zones {
+            return Ok(());
+        }
+        self.zone_mask = build_zone_mask(zones, DETECT_WIDTH, DETECT_HEIGHT)?;
+        self.zones = zones.to_vec();
+        Ok(())
+    }
+
+    /// Forgets the previous frames, used when the stream is reopened
+    pub fn reset(&mut self) -> Result<(), opencv::Error> {
+        self.detector = create_detector(&self.settings)?;
+        self.detection_count = 0;
+        self.frame_count = 0;
+        Ok(())
+    }
+
+    /// Feeds one decoded BGR frame, returns the frame's moving boxes when it completes a movement detection
+    pub fn process(&mut self, frame: &Mat) -> Result<Option<Vec<BoundingBox>>, opencv::Error> {
+        let mut rescaled = Mat::default();
+        imgproc::resize(
+            frame,
+            &mut rescaled,
+            Size::new(DETECT_WIDTH, DETECT_HEIGHT),
+            0.5,
+            0.5,
+            INTER_LINEAR,
+        )?;
+        let mut gray = Mat::default();
+        imgproc::cvt_color(&rescaled, &mut gray, COLOR_BGR2GRAY, 0)?;
+        let mut blurred = Mat::default();
+        imgproc::blur(
+            &gray,
+            &mut blurred,
+            Size::new(self.settings.blur_size, self.settings.blur_size),
+            Point::new(-1, -1),
+            BORDER_DEFAULT,
+        )?;
+
+        self.frame_count += 1;
+        let mut detected = None;
+        if let Some(boxes) = self.moving_boxes(&blurred)? {
+            for _ in boxes.iter() {
+                self.detection_count += 1;
+                if self.detection_count > self.settings.hits_per_window {
+                    detected = Some(boxes.clone());
+                    self.detection_count = 0;
+                }
+            }
+        }
+
+        if self.frame_count >= self.settings.window_frames {
+            self.frame_count = 0;
+            self.detection_count = 0;
+        }
+        Ok(detected)
+    }
+
+    /// Bounding boxes of the moving areas big enough to count, None until the detector is ready
+    fn moving_boxes(&mut self, frame: &Mat) -> Result<Option<Vec<BoundingBox>>, opencv::Error> {
+        let Some(mut mask) = self.detector.foreground_mask(frame, &self.settings)? else {
+            return Ok(None);
+        };
+        if let Some(zone_mask) = &self.zone_mask {
+            let mut masked = Mat::default();
+            opencv::core::bitwise_and(&mask, zone_mask, &mut masked, &no_array())?;
+            mask = masked;
+        }
+
+        let mut contours: Vector<Vector<Point>> = Vector::new();
+        imgproc::find_contours(
+            &mask,
+            &mut contours,
+            RETR_EXTERNAL,
+            CHAIN_APPROX_TC89_L1,
+            Point::new(0, 0),
+        )?;
+        let mut boxes = vec![];
+        for contour in contours {
+            let bb = imgproc::bounding_rect(&contour)?;
+            if bb.area() > self.settings.min_area {
+                boxes.push(BoundingBox::from_rect(&bb, DETECT_WIDTH, DETECT_HEIGHT));
+            }
+        }
+        Ok(Some(boxes))
+    }
+}
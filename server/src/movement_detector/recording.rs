@@ -0,0 +1,52 @@
+use serde::{Deserialize, Serialize};
+
+use crate::movement_detector::continuous::ContinuousConfig;
+use crate::movement_detector::janitor::ClipRetention;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum RecordingMode {
+    /// Only clips around the detected movements
+    #[default]
+    Events,
+    /// Records everything into hourly or daily files, movement clips are still saved
+    Continuous,
+}
+
+/// Per camera `[cameras.recording]` section
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct RecordingConfig {
+    pub mode: RecordingMode,
+    /// Seconds of video kept before the movement in each clip
+    pub pre_roll: u32,
+    /// Seconds without movement before a clip is closed
+    pub post_roll: u32,
+    pub continuous: ContinuousConfig,
+    pub retention: ClipRetention,
+}
+
+impl Default for RecordingConfig {
+    fn default() -> Self {
+        RecordingConfig {
+            mode: RecordingMode::Events,
+            pre_roll: 10,
+            post_roll: 5,
+            continuous: ContinuousConfig::default(),
+            retention: ClipRetention::default(),
+        }
+    }
+}
+
+impl RecordingConfig {
+    pub fn check(&self) -> Result<(), String> {
+        if self.pre_roll > 300 {
+            return Err("pre_roll can't be longer than 300 seconds".to_string());
+        }
+        if !(1..=300).contains(&self.post_roll) {
+            return Err("post_roll must be between 1 and 300 seconds".to_string());
+        }
+        self.continuous.check()?;
+        self.retention.check()
+    }
+}
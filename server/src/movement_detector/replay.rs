@@ -0,0 +1,235 @@
+use opencv::{
+    prelude::*,
+    videoio::{self, CAP_PROP_FPS},
+};
+use std::time::Instant;
+
+use crate::movement_detector::{
+    detectors::DetectionAlgorithm, pipeline::DetectionPipeline, settings::DetectionConfig,
+    zones::DetectionZone,
+};
+
+/// What a detection algorithm found in a replayed video
+#[derive(Debug)]
+pub struct ReplayReport {
+    pub frames: u64,
+    /// Seconds into the video of each movement detection
+    pub detections: Vec<f64>,
+    /// (start, end) seconds of the movement events the logger would have produced
+    pub events: Vec<(f64, f64)>,
+}
+
+/// Test harness : runs a recorded video through every detection algorithm and prints
+/// the movement events each one would have produced, closed after `post_roll` seconds
+/// without movement like the camera's logger does.
+/// Started with `nephtys-server replay <video file> [camera id]`.
+pub fn replay_file(
+    path: &str,
+    settings: DetectionConfig,
+    zones: Vec<DetectionZone>,
+    post_roll: u32,
+) -> Result<(), opencv::Error> {
+    println!("replaying {}", path);
+    for algorithm in [
+        DetectionAlgorithm::FrameDiff,
+        DetectionAlgorithm::Mog2,
+        DetectionAlgorithm::Knn,
+    ] {
+        let settings = DetectionConfig {
+            algorithm,
+            ..settings.clone()
+        };
+        let mut cam = videoio::VideoCapture::from_file(path, videoio::CAP_ANY)?;
+        if !cam.is_opened()? {
+            println!("ERROR: Couldn't open {}", path);
+            return Ok(());
+        }
+        let fps = match cam.get(CAP_PROP_FPS)? {
+            fps if fps > 0.0 => fps,
+            _ => 30.0,
+        };
+        let frames = std::iter::from_fn(|| {
+            let mut frame = Mat::default();
+            match cam.read(&mut frame) {
+                Ok(true) if !frame.empty() => Some(frame),
+                _ => None,
+            }
+        });
+
+        let started = Instant::now();
+        let report = replay_frames(frames, fps, settings, zones.clone(), post_roll)?;
+        let elapsed = started.elapsed().as_secs_f64();
+
+        println!(
+            "{:?}: {} detections over {} frames ({:.1} fps processed)",
+            algorithm,
+            report.detections.len(),
+            report.frames,
+            report.frames as f64 / elapsed.max(0.001)
+        );
+        for (start, end) in report.events.iter() {
+            println!("  movement {:.1}s - {:.1}s", start, end);
+        }
+    }
+    Ok(())
+}
+
+/// Runs decoded BGR frames through a detection pipeline
+pub fn replay_frames(
+    frames: impl Iterator<Item = Mat>,
+    fps: f64,
+    settings: DetectionConfig,
+    zones: Vec<DetectionZone>,
+    post_roll: u32,
+) -> Result<ReplayReport, opencv::Error> {
+    let mut pipeline = DetectionPipeline::new(settings, zones)?;
+    let mut frame_index: u64 = 0;
+    let mut detections: Vec<f64> = vec![];
+    for frame in frames {
+        if pipeline.process(&frame)?.is_some() {
+            detections.push(frame_index as f64 / fps);
+        }
+        frame_index += 1;
+    }
+    Ok(ReplayReport {
+        frames: frame_index,
+        events: group_events(&detections, post_roll as f64),
+        detections,
+    })
+}
+
+/// Merges detection times at most `gap` seconds apart into (start, end) events
+fn group_events(detections: &[f64], gap: f64) -> Vec<(f64, f64)> {
+    let mut events: Vec<(f64, f64)> = vec![];
+    for time in detections {
+        match events.last_mut() {
+            Some((_, end)) if time - *end <= gap => *end = *time,
+            _ => events.push((*time, *time)),
+        }
+    }
+    events
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::movement_detector::{DETECT_HEIGHT, DETECT_WIDTH};
+    use opencv::{
+        core::{CV_8UC3, Rect, Scalar},
+        imgproc,
+    };
+
+    const FPS: f64 = 30.0;
+    const POST_ROLL: u32 = 5;
+    const LEARNING_SECS: f64 = 1.0;
+
+    /// Black frame, with a white square at `x` when given
+    fn frame(square_x: Option<i32>) -> Mat {
+        let mut frame =
+            Mat::new_rows_cols_with_default(DETECT_HEIGHT, DETECT_WIDTH, CV_8UC3, Scalar::all(0.0))
+                .unwrap();
+        if let Some(x) = square_x {
+            imgproc::rectangle(
+                &mut frame,
+                Rect::new(x, 140, 80, 80),
+                Scalar::all(255.0),
+                -1,
+                imgproc::LINE_8,
+                0,
+            )
+            .unwrap();
+        }
+        frame
+    }
+
+    /// `still` seconds of empty scene, then a square crossing the frame in 2 seconds
+    fn scene(still: f64) -> Vec<Mat> {
+        let mut frames: Vec<Mat> = (0..(still * FPS) as i32).map(|_| frame(None)).collect();
+        frames.extend((0..(2.0 * FPS) as i32).map(|i| frame(Some(i * 9))));
+        frames
+    }
+
+    /// Background models flag the first frames while they learn the empty scene
+    fn after_learning(report: &ReplayReport) -> Vec<(f64, f64)> {
+        report
+            .events
+            .iter()
+            .copied()
+            .filter(|(_, end)| *end >= LEARNING_SECS)
+            .collect()
+    }
+
+    fn replay(frames: Vec<Mat>, algorithm: DetectionAlgorithm) -> ReplayReport {
+        let settings = DetectionConfig {
+            algorithm,
+            ..DetectionConfig::default()
+        };
+        replay_frames(frames.into_iter(), FPS, settings, vec![], POST_ROLL).unwrap()
+    }
+
+    /// Two crossings 10s apart, then 2s of empty scene
+    fn two_movements() -> Vec<Mat> {
+        let mut frames = scene(2.0);
+        frames.extend(scene(10.0));
+        frames.extend((0..(2.0 * FPS) as i32).map(|_| frame(None)));
+        frames
+    }
+
+    #[test]
+    fn groups_close_detections_into_events() {
+        let events = group_events(&[1.0, 2.0, 6.5, 20.0, 21.0], 5.0);
+        assert_eq!(events, vec![(1.0, 6.5), (20.0, 21.0)]);
+        assert!(group_events(&[], 5.0).is_empty());
+    }
+
+    #[test]
+    fn post_roll_sets_the_gap_between_events() {
+        let detections = [1.0, 2.0, 6.5, 20.0, 21.0];
+        assert_eq!(group_events(&detections, 1.0).len(), 3);
+        assert_eq!(group_events(&detections, 30.0), vec![(1.0, 21.0)]);
+    }
+
+    #[test]
+    fn still_scene_has_no_event() {
+        for algorithm in [
+            DetectionAlgorithm::FrameDiff,
+            DetectionAlgorithm::Mog2,
+            DetectionAlgorithm::Knn,
+        ] {
+            let still = scene(10.0).into_iter().take((10.0 * FPS) as usize);
+            let report = replay(still.collect(), algorithm);
+            assert_eq!(report.frames, 300);
+            assert!(
+                after_learning(&report).is_empty(),
+                "{:?}: {:?}",
+                algorithm,
+                report.events
+            );
+        }
+    }
+
+    #[test]
+    fn frame_diff_separates_movements_apart() {
+        let report = replay(two_movements(), DetectionAlgorithm::FrameDiff);
+        assert_eq!(report.events.len(), 2, "{:?}", report.events);
+    }
+
+    #[test]
+    fn mog2_separates_movements_apart() {
+        let report = replay(two_movements(), DetectionAlgorithm::Mog2);
+        let events = after_learning(&report);
+        assert_eq!(events.len(), 2, "{:?}", report.events);
+        // Each event covers its crossing
+        assert!(events[0].1 >= 2.0 && events[0].1 < 5.0, "{:?}", events);
+        assert!(events[1].0 >= 14.0 && events[1].0 < 16.0, "{:?}", events);
+    }
+
+    #[test]
+    fn knn_separates_movements_apart() {
+        let report = replay(two_movements(), DetectionAlgorithm::Knn);
+        let events = after_learning(&report);
+        assert_eq!(events.len(), 2, "{:?}", report.events);
+        assert!(events[0].1 >= 2.0 && events[0].1 < 5.0, "{:?}", events);
+        assert!(events[1].0 >= 14.0 && events[1].0 < 16.0, "{:?}", events);
+    }
+}
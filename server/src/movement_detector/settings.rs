@@ -0,0 +1,73 @@
+use serde::{Deserialize, Serialize};
+
+use crate::movement_detector::detectors::DetectionAlgorithm;
+
+/// Per camera `[cameras.detection]` section, can be changed at runtime through the API
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct DetectionConfig {
+    pub algorithm: DetectionAlgorithm,
+    /// Frames remembered by the background model (mog2 & knn)
+    pub history: i32,
+    /// Background model sensitivity, lower detects more (mog2 defaults to 16, knn to 400)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub background_threshold: Option<f64>,
+    /// Size of the box blur applied before comparing frames (px)
+    pub blur_size: i32,
+    /// Neighbourhood of the adaptive threshold, must be odd (px)
+    pub threshold_block_size: i32,
+    /// Constant subtracted by the adaptive threshold, higher ignores fainter changes
+    pub threshold_c: f64,
+    /// Smallest bounding box counted as movement (px², on the 640x360 detection frame)
+    pub min_area: i32,
+    /// Movement is reported once more than this many boxes were seen during a window
+    pub hits_per_window: u32,
+    /// Length of a detection window (frames)
+    pub window_frames: u32,
+}
+
+impl Default for DetectionConfig {
+    fn default() -> Self {
+        DetectionConfig {
+            algorithm: DetectionAlgorithm::FrameDiff,
+            history: 500,
+            background_threshold: None,
+            blur_size: 10,
+            threshold_block_size: 11,
+            threshold_c: 3.0,
+            min_area: 250,
+            hits_per_window: 10,
+            window_frames: 30,
+        }
+    }
+}
+
+impl DetectionConfig {
+    /// Checks the values before they reach OpenCV, returns a human readable error otherwise
+    pub fn check(&self) -> Result<(), String> {
+        if self.history < 1 {
+            return Err("history must be at least 1 frame".to_string());
+        }
+        if let Some(threshold) = self.background_threshold
+            && !(threshold.is_finite() && threshold > 0.0)
+        {
+            return Err("background_threshold must be greater than 0".to_string());
+        }
+        if !(1..=100).contains(&self.blur_size) {
+            return Err("blur_size must be between 1 and 100".to_string());
+        }
+        if self.threshold_block_size < 3 || self.threshold_block_size % 2 == 0 {
+            return Err("threshold_block_size must be odd and at least 3".to_string());
+        }
+        if !self.threshold_c.is_finite() {
+            return Err("threshold_c must be a number".to_string());
+        }
+        if self.min_area < 0 {
+            return Err("min_area can't be negative".to_string());
+        }
+        if self.window_frames == 0 {
+            return Err("window_frames must be at least 1".to_string());
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,90 @@
+use opencv::{
+    core::{CV_8UC1, Point, Scalar, Vector},
+    imgproc::{self, LINE_8},
+    prelude::*,
+};
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ZoneKind {
+    /// Only movement inside include zones is detected
+    Include,
+    /// Movement inside exclude zones is ignored
+    Exclude,
+}
+
+/// Polygon drawn over the camera's picture.
+/// Points are `[x, y]` fractions of the frame (0.0 - 1.0) so they don't depend on the resolution.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct DetectionZone {
+    pub kind: ZoneKind,
+    pub points: Vec<[f32; 2]>,
+}
+
+/// Checks the zones received from the API or config.toml, returns a human readable error otherwise
+pub fn check_zones(zones: &[DetectionZone]) -> Result<(), String> {
+    for (i, zone) in zones.iter().enumerate() {
+        if zone.points.len() < 3 {
+            return Err(format!("zone {} needs at least 3 points", i));
+        }
+        let in_frame = zone
+            .points
+            .iter()
+            .all(|[x, y]| (0.0..=1.0).contains(x) && (0.0..=1.0).contains(y));
+        if !in_frame {
+            return Err(format!(
+                "zone {} has points outside of the 0.0 - 1.0 range",
+                i
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Builds a 8 bit mask of the given size : 255 where movement counts, 0 where it's ignored.
+/// Returns None when there are no zones (the whole frame counts).
+pub fn build_zone_mask(
+    zones: &[DetectionZone],
+    width: i32,
+    height: i32,
+) -> Result<Option<Mat>, opencv::Error> {
+    if zones.is_empty() {
+        return Ok(None);
+    }
+    let has_include = zones.iter().any(|zone| zone.kind == ZoneKind::Include);
+    let background = if has_include { 0.0 } else { 255.0 };
+    let mut mask =
+        Mat::new_rows_cols_with_default(height, width, CV_8UC1, Scalar::all(background))?;
+
+    // Exclude zones are drawn last so they win over include zones
+    for kind in [ZoneKind::Include, ZoneKind::Exclude] {
+        let polygons: Vector<Vector<Point>> = zones
+            .iter()
+            .filter(|zone| zone.kind == kind)
+            .map(|zone| {
+                zone.points
+                    .iter()
+                    .map(|[x, y]| Point::new((x * width as f32) as i32, (y * height as f32) as i32))
+                    .collect()
+            })
+            .collect();
+        if polygons.is_empty() {
+            continue;
+        }
+        let color = if kind == ZoneKind::Include {
+            255.0
+        } else {
+            0.0
+        };
+        imgproc::fill_poly(
+            &mut mask,
+            &polygons,
+            Scalar::all(color),
+            LINE_8,
+            0,
+            Point::new(0, 0),
+        )?;
+    }
+    Ok(Some(mask))
+}
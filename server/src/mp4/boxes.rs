@@ -0,0 +1,226 @@
+use crate::mp4::RemuxError;
+
+// Boxes holding other boxes, everything else is kept as raw bytes
+const CONTAINERS: [&[u8; 4]; 10] = [
+    b"moov", b"trak", b"mdia", b"minf", b"stbl", b"edts", b"dinf", b"mvex", b"moof", b"traf",
+];
+
+/// Top level box of a file, borrowing its payload
+pub struct RawBox<'a> {
+    pub kind: [u8; 4],
+    /// Position of the box header in the parsed data
+    pub offset: usize,
+    pub payload: &'a [u8],
+}
+
+/// Splits `data` in consecutive boxes
+pub fn split_boxes(data: &[u8]) -> Result<Vec<RawBox<'_>>, RemuxError> {
+    let mut boxes = vec![];
+    let mut offset = 0;
+    while offset < data.len() {
+        let mut reader = Reader::new(&data[offset..]);
+        let size = reader.u32()? as u64;
+        let kind: [u8; 4] = reader.bytes(4)?.try_into().unwrap();
+        let (header, size) = match size {
+            // Box goes to the end of the data
+            0 => (8, (data.len() - offset) as u64),
+            1 => (16, reader.u64()?),
+            size => (8, size),
+        };
+        if size < header as u64 || offset as u64 + size > data.len() as u64 {
+            return Err(RemuxError::Invalid(format!(
+                "box {} at {} is truncated",
+                String::from_utf8_lossy(&kind),
+                offset
+            )));
+        }
+        let end = offset + size as usize;
+        boxes.push(RawBox {
+            kind,
+            offset,
+            payload: &data[offset + header..end],
+        });
+        offset = end;
+    }
+    Ok(boxes)
+}
+
+/// Owned box tree, used to rewrite the init segment's moov
+#[derive(Clone, Debug)]
+pub enum Mp4Box {
+    Container {
+        kind: [u8; 4],
+        children: Vec<Mp4Box>,
+    },
+    Leaf {
+        kind: [u8; 4],
+        payload: Vec<u8>,
+    },
+}
+
+impl Mp4Box {
+    pub fn parse_all(data: &[u8]) -> Result<Vec<Mp4Box>, RemuxError> {
+        split_boxes(data)?
+            .into_iter()
+            .map(|raw| Mp4Box::parse(raw.kind, raw.payload))
+            .collect()
+    }
+
+    fn parse(kind: [u8; 4], payload: &[u8]) -> Result<Mp4Box, RemuxError> {
+        if CONTAINERS.contains(&&kind) {
+            Ok(Mp4Box::Container {
+                kind,
+                children: Mp4Box::parse_all(payload)?,
+            })
+        } else {
+            Ok(Mp4Box::Leaf {
+                kind,
+                payload: payload.to_vec(),
+            })
+        }
+    }
+
+    pub fn leaf(kind: &[u8; 4], payload: Vec<u8>) -> Mp4Box {
+        Mp4Box::Leaf {
+            kind: *kind,
+            payload,
+        }
+    }
+
+    pub fn kind(&self) -> &[u8; 4] {
+        match self {
+            Mp4Box::Container { kind, .. } | Mp4Box::Leaf { kind, .. } => kind,
+        }
+    }
+
+    /// Leaf payload, empty for containers
+    pub fn payload(&self) -> &[u8] {
+        match self {
+            Mp4Box::Container { .. } => &[],
+            Mp4Box::Leaf { payload, .. } => payload,
+        }
+    }
+
+    pub fn payload_mut(&mut self) -> Option<&mut Vec<u8>> {
+        match self {
+            Mp4Box::Container { .. } => None,
+            Mp4Box::Leaf { payload, .. } => Some(payload),
+        }
+    }
+
+    pub fn children(&self) -> &[Mp4Box] {
+        match self {
+            Mp4Box::Container { children, .. } => children,
+            Mp4Box::Leaf { .. } => &[],
+        }
+    }
+
+    pub fn children_mut(&mut self) -> Option<&mut Vec<Mp4Box>> {
+        match self {
+            Mp4Box::Container { children, .. } => Some(children),
+            Mp4Box::Leaf { .. } => None,
+        }
+    }
+
+    /// First child box of this kind
+    pub fn child(&self, kind: &[u8; 4]) -> Option<&Mp4Box> {
+        self.children().iter().find(|child| child.kind() == kind)
+    }
+
+    pub fn child_mut(&mut self, kind: &[u8; 4]) -> Option<&mut Mp4Box> {
+        self.children_mut()?
+            .iter_mut()
+            .find(|child| child.kind() == kind)
+    }
+
+    /// Follows a path of nested boxes, `["mdia", "mdhd"]` from a trak for example
+    pub fn find(&self, path: &[&[u8; 4]]) -> Option<&Mp4Box> {
+        path.iter()
+            .try_fold(self, |current, kind| current.child(kind))
+    }
+
+    pub fn find_mut(&mut self, path: &[&[u8; 4]]) -> Option<&mut Mp4Box> {
+        path.iter()
+            .try_fold(self, |current, kind| current.child_mut(kind))
+    }
+
+    pub fn size(&self) -> u64 {
+        let content = match self {
+            Mp4Box::Container { children, .. } => children.iter().map(Mp4Box::size).sum(),
+            Mp4Box::Leaf { payload, .. } => payload.len() as u64,
+        };
+        if content + 8 > u32::MAX as u64 {
+            content + 16
+        } else {
+            content + 8
+        }
+    }
+
+    pub fn write(&self, out: &mut Vec<u8>) {
+        let size = self.size();
+        if size > u32::MAX as u64 {
+            push_u32(out, 1);
+            out.extend_from_slice(self.kind());
+            push_u64(out, size);
+        } else {
+            push_u32(out, size as u32);
+            out.extend_from_slice(self.kind());
+        }
+        match self {
+            Mp4Box::Container { children, .. } => {
+                for child in children {
+                    child.write(out);
+                }
+            }
+            Mp4Box::Leaf { payload, .. } => out.extend_from_slice(payload),
+        }
+    }
+}
+
+/// Big endian reader over a box payload
+pub struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(data: &'a [u8]) -> Reader<'a> {
+        Reader { data, pos: 0 }
+    }
+
+    pub fn bytes(&mut self, len: usize) -> Result<&'a [u8], RemuxError> {
+        let end = self.pos + len;
+        if end > self.data.len() {
+            return Err(RemuxError::Invalid("unexpected end of box".to_string()));
+        }
+        let bytes = &self.data[self.pos..end];
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    pub fn skip(&mut self, len: usize) -> Result<(), RemuxError> {
+        self.bytes(len).map(|_| ())
+    }
+
+    pub fn u32(&mut self) -> Result<u32, RemuxError> {
+        Ok(u32::from_be_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+
+    pub fn u64(&mut self) -> Result<u64, RemuxError> {
+        Ok(u64::from_be_bytes(self.bytes(8)?.try_into().unwrap()))
+    }
+
+    /// Version & flags of a full box
+    pub fn version_flags(&mut self) -> Result<(u8, u32), RemuxError> {
+        let value = self.u32()?;
+        Ok(((value >> 24) as u8, value & 0x00FF_FFFF))
+    }
+}
+
+pub fn push_u32(out: &mut Vec<u8>, value: u32) {
+    out.extend_from_slice(&value.to_be_bytes());
+}
+
+pub fn push_u64(out: &mut Vec<u8>, value: u64) {
+    out.extend_from_slice(&value.to_be_bytes());
+}
@@ -0,0 +1,197 @@
+use std::collections::HashMap;
+
+use crate::mp4::{
+    RemuxError,
+    boxes::{Mp4Box, Reader, split_boxes},
+};
+
+// tfhd flags
+const BASE_DATA_OFFSET: u32 = 0x01;
+const SAMPLE_DESCRIPTION_INDEX: u32 = 0x02;
+const DEFAULT_DURATION: u32 = 0x08;
+const DEFAULT_SIZE: u32 = 0x10;
+const DEFAULT_FLAGS: u32 = 0x20;
+// trun flags
+const DATA_OFFSET: u32 = 0x01;
+const FIRST_SAMPLE_FLAGS: u32 = 0x04;
+const SAMPLE_DURATION: u32 = 0x100;
+const SAMPLE_SIZE: u32 = 0x200;
+const SAMPLE_FLAGS: u32 = 0x400;
+const SAMPLE_CTS_OFFSET: u32 = 0x800;
+// sample_is_non_sync_sample bit of the sample flags
+const NON_SYNC: u32 = 0x0001_0000;
+
+/// Per track sample defaults, from the init segment's trex boxes
+#[derive(Clone, Copy, Default)]
+pub struct SampleDefaults {
+    pub duration: u32,
+    pub size: u32,
+    pub flags: u32,
+}
+
+/// trex boxes of the init segment's moov, by track id
+pub fn track_defaults(moov: &Mp4Box) -> Result<HashMap<u32, SampleDefaults>, RemuxError> {
+    let mut defaults = HashMap::new();
+    let Some(mvex) = moov.child(b"mvex") else {
+        return Ok(defaults);
+    };
+    for trex in mvex.children().iter().filter(|b| b.kind() == b"trex") {
+        let mut reader = Reader::new(trex.payload());
+        reader.version_flags()?;
+        let track_id = reader.u32()?;
+        reader.skip(4)?; // sample description index
+        defaults.insert(
+            track_id,
+            SampleDefaults {
+                duration: reader.u32()?,
+                size: reader.u32()?,
+                flags: reader.u32()?,
+            },
+        );
+    }
+    Ok(defaults)
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Sample {
+    pub size: u32,
+    pub duration: u32,
+    pub cts_offset: i64,
+    pub sync: bool,
+}
+
+/// Samples of one track stored one after the other in a segment
+#[derive(Debug)]
+pub struct Chunk {
+    pub track_id: u32,
+    /// Position of the first sample in the segment file
+    pub offset: usize,
+    pub len: usize,
+    pub samples: Vec<Sample>,
+}
+
+/// Lists the chunks of every moof / mdat pair of an fMP4 segment, in file order
+pub fn parse_segment(
+    data: &[u8],
+    defaults: &HashMap<u32, SampleDefaults>,
+) -> Result<Vec<Chunk>, RemuxError> {
+    let mut chunks = vec![];
+    for moof in split_boxes(data)?
+        .into_iter()
+        .filter(|b| &b.kind == b"moof")
+    {
+        for traf in Mp4Box::parse_all(moof.payload)?
+            .iter()
+            .filter(|b| b.kind() == b"traf")
+        {
+            parse_traf(traf, moof.offset, defaults, &mut chunks)?;
+        }
+    }
+    for chunk in chunks.iter() {
+        if chunk.offset + chunk.len > data.len() {
+            return Err(RemuxError::Invalid(format!(
+                "samples of track {} go past the end of the segment",
+                chunk.track_id
+            )));
+        }
+    }
+    Ok(chunks)
+}
+
+fn parse_traf(
+    traf: &Mp4Box,
+    moof_offset: usize,
+    defaults: &HashMap<u32, SampleDefaults>,
+    chunks: &mut Vec<Chunk>,
+) -> Result<(), RemuxError> {
+    let tfhd = traf
+        .child(b"tfhd")
+        .ok_or_else(|| RemuxError::Invalid("traf without tfhd".to_string()))?;
+    let mut reader = Reader::new(tfhd.payload());
+    let (_, flags) = reader.version_flags()?;
+    let track_id = reader.u32()?;
+    let mut track = defaults.get(&track_id).copied().unwrap_or_default();
+    // ffmpeg sets default-base-is-moof, which is also the fallback without an explicit base
+    let base = if flags & BASE_DATA_OFFSET != 0 {
+        reader.u64()? as usize
+    } else {
+        moof_offset
+    };
+    if flags & SAMPLE_DESCRIPTION_INDEX != 0 {
+        reader.skip(4)?;
+    }
+    if flags & DEFAULT_DURATION != 0 {
+        track.duration = reader.u32()?;
+    }
+    if flags & DEFAULT_SIZE != 0 {
+        track.size = reader.u32()?;
+    }
+    if flags & DEFAULT_FLAGS != 0 {
+        track.flags = reader.u32()?;
+    }
+
+    let mut pos = base;
+    for trun in traf.children().iter().filter(|b| b.kind() == b"trun") {
+        let mut reader = Reader::new(trun.payload());
+        let (version, flags) = reader.version_flags()?;
+        let count = reader.u32()?;
+        if flags & DATA_OFFSET != 0 {
+            let data_offset = reader.u32()? as i32;
+            pos = base
+                .checked_add_signed(data_offset as isize)
+                .ok_or_else(|| RemuxError::Invalid("negative trun data offset".to_string()))?;
+        }
+        let first_flags = if flags & FIRST_SAMPLE_FLAGS != 0 {
+            Some(reader.u32()?)
+        } else {
+            None
+        };
+
+        let mut chunk = Chunk {
+            track_id,
+            offset: pos,
+            len: 0,
+            samples: Vec::with_capacity(count as usize),
+        };
+        for i in 0..count {
+            let duration = if flags & SAMPLE_DURATION != 0 {
+                reader.u32()?
+            } else {
+                track.duration
+            };
+            let size = if flags & SAMPLE_SIZE != 0 {
+                reader.u32()?
+            } else {
+                track.size
+            };
+            let sample_flags = if flags & SAMPLE_FLAGS != 0 {
+                reader.u32()?
+            } else if i == 0
+                && let Some(first_flags) = first_flags
+            {
+                first_flags
+            } else {
+                track.flags
+            };
+            let cts_offset = if flags & SAMPLE_CTS_OFFSET == 0 {
+                0
+            } else if version == 0 {
+                reader.u32()? as i64
+            } else {
+                reader.u32()? as i32 as i64
+            };
+            chunk.len += size as usize;
+            chunk.samples.push(Sample {
+                size,
+                duration,
+                cts_offset,
+                sync: sample_flags & NON_SYNC == 0,
+            });
+        }
+        pos += chunk.len;
+        if !chunk.samples.is_empty() {
+            chunks.push(chunk);
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,709 @@
+use std::{
+    fmt, fs,
+    io::{self, BufWriter, Read, Seek, SeekFrom, Write},
+};
+
+use crate::mp4::boxes::{Mp4Box, Reader, push_u32, push_u64};
+use crate::mp4::fragments::{Chunk, Sample, parse_segment, track_defaults};
+pub mod boxes;
+pub mod fragments;
+
+#[derive(Debug)]
+pub enum RemuxError {
+    Io(io::Error),
+    /// The input or the written file isn't what we expect
+    Invalid(String),
+}
+
+impl fmt::Display for RemuxError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RemuxError::Io(err) => write!(f, "{}", err),
+            RemuxError::Invalid(reason) => write!(f, "{}", reason),
+        }
+    }
+}
+
+impl From<io::Error> for RemuxError {
+    fn from(err: io::Error) -> Self {
+        RemuxError::Io(err)
+    }
+}
+
+/// What the written file contains, read back from it
+#[derive(Debug)]
+pub struct RemuxSummary {
+    /// Seconds
+    pub duration: f64,
+    pub samples: u64,
+}
+
+/// Samples & chunks of one track of the output
+struct Track {
+    id: u32,
+    samples: Vec<Sample>,
+    /// Samples in each chunk & their position relative to the start of mdat's payload
+    chunks: Vec<(u32, u64)>,
+}
+
+impl Track {
+    fn duration(&self) -> u64 {
+        self.samples.iter().map(|s| s.duration as u64).sum()
+    }
+}
+
+/// Joins an fMP4 init segment and its media segments (in the given order) into a standalone,
+/// seekable MP4 with its moov up front. The file is written next to `output` then moved in place
+/// once read back & checked.
+pub fn remux_fragments(
+    init: &str,
+    segments: &[String],
+    output: &str,
+) -> Result<RemuxSummary, RemuxError> {
+    let init_boxes = Mp4Box::parse_all(&fs::read(init)?)?;
+    let init_moov = init_boxes
+        .iter()
+        .find(|b| b.kind() == b"moov")
+        .ok_or_else(|| RemuxError::Invalid(format!("{} has no moov", init)))?;
+    let defaults = track_defaults(init_moov)?;
+    let mut tracks: Vec<Track> = vec![];
+    for trak in init_moov.children().iter().filter(|b| b.kind() == b"trak") {
+        let tkhd = trak
+            .child(b"tkhd")
+            .ok_or_else(|| RemuxError::Invalid("trak without tkhd".to_string()))?;
+        tracks.push(Track {
+            id: tkhd_track_id(tkhd.payload())?,
+            samples: vec![],
+            chunks: vec![],
+        });
+    }
+
+    // First pass : sample tables & where each chunk will be in mdat
+    let mut layout: Vec<Vec<Chunk>> = vec![];
+    let mut mdat_len: u64 = 0;
+    for segment in segments {
+        let chunks = parse_segment(&fs::read(segment)?, &defaults)?;
+        for chunk in chunks.iter() {
+            let Some(track) = tracks.iter_mut().find(|t| t.id == chunk.track_id) else {
+                return Err(RemuxError::Invalid(format!(
+                    "{} has samples of unknown track {}",
+                    segment, chunk.track_id
+                )));
+            };
+            track.chunks.push((chunk.samples.len() as u32, mdat_len));
+            track.samples.extend(chunk.samples.iter());
+            mdat_len += chunk.len as u64;
+        }
+        layout.push(chunks);
+    }
+    let samples: u64 = tracks.iter().map(|t| t.samples.len() as u64).sum();
+    if samples == 0 {
+        return Err(RemuxError::Invalid("no samples to write".to_string()));
+    }
+
+    // co64 entries have a fixed size : the moov size doesn't depend on where mdat starts
+    let ftyp = ftyp();
+    let header_len = ftyp.size() + build_moov(init_moov, &tracks, 0)?.size() + 16;
+    let moov = build_moov(init_moov, &tracks, header_len)?;
+
+    let part = format!("{}.part", output);
+    let written = write_mp4(&part, &ftyp, &moov, mdat_len, segments, &layout)
+        .and_then(|_| check_mp4(&part, samples, mdat_len));
+    match written {
+        Ok(summary) => {
+            fs::rename(&part, output)?;
+            Ok(summary)
+        }
+        Err(err) => {
+            let _ = fs::remove_file(&part);
+            Err(err)
+        }
+    }
+}
+
+fn write_mp4(
+    path: &str,
+    ftyp: &Mp4Box,
+    moov: &Mp4Box,
+    mdat_len: u64,
+    segments: &[String],
+    layout: &[Vec<Chunk>],
+) -> Result<(), RemuxError> {
+    let mut header = vec![];
+    ftyp.write(&mut header);
+    moov.write(&mut header);
+    // 64 bits mdat size, clips can get bigger than 4 GiB
+    push_u32(&mut header, 1);
+    header.extend_from_slice(b"mdat");
+    push_u64(&mut header, mdat_len + 16);
+
+    let mut file = BufWriter::new(fs::File::create(path)?);
+    file.write_all(&header)?;
+    // Second pass : copies the samples, each segment is read once more
+    for (segment, chunks) in segments.iter().zip(layout) {
+        let data = fs::read(segment)?;
+        for chunk in chunks {
+            file.write_all(&data[chunk.offset..chunk.offset + chunk.len])?;
+        }
+    }
+    file.flush()?;
+    file.get_ref().sync_all()?;
+    Ok(())
+}
+
+fn ftyp() -> Mp4Box {
+    let mut payload = b"isom".to_vec();
+    push_u32(&mut payload, 0x200);
+    for brand in [b"isom", b"iso2", b"mp41"] {
+        payload.extend_from_slice(brand);
+    }
+    Mp4Box::leaf(b"ftyp", payload)
+}
+
+fn tkhd_track_id(tkhd: &[u8]) -> Result<u32, RemuxError> {
+    let mut reader = Reader::new(tkhd);
+    let (version, _) = reader.version_flags()?;
+    reader.skip(if version == 1 { 16 } else { 8 })?;
+    reader.u32()
+}
+
+/// Timescale of a mvhd or mdhd, both start the same way
+fn timescale(header: &[u8]) -> Result<u32, RemuxError> {
+    let mut reader = Reader::new(header);
+    let (version, _) = reader.version_flags()?;
+    reader.skip(if version == 1 { 16 } else { 8 })?;
+    reader.u32()
+}
+
+/// Writes the duration of a mvhd, mdhd or tkhd at its version dependent position
+fn set_duration(header: &mut [u8], v0_offset: usize, v1_offset: usize, duration: u64) {
+    if header.first() == Some(&1) && header.len() >= v1_offset + 8 {
+        header[v1_offset..v1_offset + 8].copy_from_slice(&duration.to_be_bytes());
+    } else if header.len() >= v0_offset + 4 {
+        let duration = duration.min(u32::MAX as u64) as u32;
+        header[v0_offset..v0_offset + 4].copy_from_slice(&duration.to_be_bytes());
+    }
+}
+
+/// The init segment's moov with durations & sample tables filled, and without mvex
+fn build_moov(init_moov: &Mp4Box, tracks: &[Track], mdat_start: u64) -> Result<Mp4Box, RemuxError> {
+    let mut moov = init_moov.clone();
+    let children = moov.children_mut().unwrap();
+    children.retain(|b| b.kind() != b"mvex");
+    let movie_timescale = timescale(
+        moov.child(b"mvhd")
+            .ok_or_else(|| RemuxError::Invalid("moov without mvhd".to_string()))?
+            .payload(),
+    )? as u64;
+
+    let mut movie_duration = 0;
+    for trak in moov.children_mut().unwrap().iter_mut() {
+        if trak.kind() != b"trak" {
+            continue;
+        }
+        let Some(tkhd) = trak.child(b"tkhd") else {
+            continue;
+        };
+        let id = tkhd_track_id(tkhd.payload())?;
+        let Some(track) = tracks.iter().find(|t| t.id == id) else {
+            continue;
+        };
+        let mdhd = trak
+            .find_mut(&[b"mdia", b"mdhd"])
+            .and_then(Mp4Box::payload_mut)
+            .ok_or_else(|| RemuxError::Invalid(format!("track {} has no mdhd", id)))?;
+        let media_timescale = timescale(mdhd)?.max(1) as u64;
+        let media_duration = track.duration();
+        set_duration(mdhd, 16, 24, media_duration);
+        let duration = media_duration * movie_timescale / media_timescale;
+        movie_duration = movie_duration.max(duration);
+
+        if let Some(tkhd) = trak.child_mut(b"tkhd").and_then(Mp4Box::payload_mut) {
+            set_duration(tkhd, 20, 28, duration);
+        }
+        if let Some(elst) = trak
+            .find_mut(&[b"edts", b"elst"])
+            .and_then(Mp4Box::payload_mut)
+        {
+            fill_edit_list(elst, duration);
+        }
+        let stbl = trak
+            .find_mut(&[b"mdia", b"minf", b"stbl"])
+            .and_then(Mp4Box::children_mut)
+            .ok_or_else(|| RemuxError::Invalid(format!("track {} has no stbl", id)))?;
+        stbl.retain(|b| b.kind() == b"stsd");
+        stbl.extend(sample_tables(track, mdat_start));
+    }
+    if let Some(mvhd) = moov.child_mut(b"mvhd").and_then(Mp4Box::payload_mut) {
+        set_duration(mvhd, 16, 24, movie_duration);
+    }
+    Ok(moov)
+}
+
+/// Fragmented files leave the edit durations at 0 ("until the end"), which isn't allowed otherwise
+fn fill_edit_list(elst: &mut [u8], duration: u64) {
+    let version = elst.first().copied().unwrap_or(0);
+    let (entry_len, duration_len) = if version == 1 { (20, 8) } else { (12, 4) };
+    let mut offset = 8;
+    while offset + entry_len <= elst.len() {
+        let field = &mut elst[offset..offset + duration_len];
+        if field.iter().all(|&b| b == 0) {
+            let bytes = duration.to_be_bytes();
+            field.copy_from_slice(&bytes[8 - duration_len..]);
+        }
+        offset += entry_len;
+    }
+}
+
+/// Run length encodes consecutive equal values
+fn runs<T: PartialEq + Copy>(values: impl Iterator<Item = T>) -> Vec<(u32, T)> {
+    let mut runs: Vec<(u32, T)> = vec![];
+    for value in values {
+        match runs.last_mut() {
+            Some((count, last)) if *last == value => *count += 1,
+            _ => runs.push((1, value)),
+        }
+    }
+    runs
+}
+
+fn full_box(kind: &[u8; 4], version: u8, fill: impl FnOnce(&mut Vec<u8>)) -> Mp4Box {
+    let mut payload = vec![];
+    push_u32(&mut payload, (version as u32) << 24);
+    fill(&mut payload);
+    Mp4Box::leaf(kind, payload)
+}
+
+fn sample_tables(track: &Track, mdat_start: u64) -> Vec<Mp4Box> {
+    let mut tables = vec![];
+    let durations = runs(track.samples.iter().map(|s| s.duration));
+    tables.push(full_box(b"stts", 0, |p| {
+        push_u32(p, durations.len() as u32);
+        for (count, duration) in durations {
+            push_u32(p, count);
+            push_u32(p, duration);
+        }
+    }));
+
+    if track.samples.iter().any(|s| s.cts_offset != 0) {
+        let negative = track.samples.iter().any(|s| s.cts_offset < 0);
+        let offsets = runs(track.samples.iter().map(|s| s.cts_offset));
+        tables.push(full_box(b"ctts", negative as u8, |p| {
+            push_u32(p, offsets.len() as u32);
+            for (count, offset) in offsets {
+                push_u32(p, count);
+                push_u32(p, offset as i32 as u32);
+            }
+        }));
+    }
+
+    // Without stss every sample is a sync sample
+    if track.samples.iter().any(|s| !s.sync) {
+        let sync: Vec<u32> = (1..)
+            .zip(track.samples.iter())
+            .filter(|(_, s)| s.sync)
+            .map(|(i, _)| i)
+            .collect();
+        tables.push(full_box(b"stss", 0, |p| {
+            push_u32(p, sync.len() as u32);
+            for i in sync {
+                push_u32(p, i);
+            }
+        }));
+    }
+
+    tables.push(full_box(b"stsz", 0, |p| {
+        push_u32(p, 0);
+        push_u32(p, track.samples.len() as u32);
+        for sample in track.samples.iter() {
+            push_u32(p, sample.size);
+        }
+    }));
+
+    let mut stsc = vec![];
+    let mut first_chunk = 1;
+    for (chunks, samples) in runs(track.chunks.iter().map(|(samples, _)| *samples)) {
+        stsc.push((first_chunk, samples));
+        first_chunk += chunks;
+    }
+    tables.push(full_box(b"stsc", 0, |p| {
+        push_u32(p, stsc.len() as u32);
+        for (first_chunk, samples) in stsc {
+            push_u32(p, first_chunk);
+            push_u32(p, samples);
+            push_u32(p, 1); // sample description index
+        }
+    }));
+
+    tables.push(full_box(b"co64", 0, |p| {
+        push_u32(p, track.chunks.len() as u32);
+        for (_, offset) in track.chunks.iter() {
+            push_u64(p, mdat_start + offset);
+        }
+    }));
+    tables
+}
+
+/// Reads the written file's top level boxes back and checks they match what was meant to be written
+fn check_mp4(path: &str, samples: u64, mdat_len: u64) -> Result<RemuxSummary, RemuxError> {
+    let mut file = fs::File::open(path)?;
+    let file_len = file.metadata()?.len();
+    let mut moov = None;
+    let mut mdat_found = false;
+    let mut offset = 0;
+    while offset < file_len {
+        let mut header = [0u8; 16];
+        file.seek(SeekFrom::Start(offset))?;
+        file.read_exact(&mut header[..8])?;
+        let kind: [u8; 4] = header[4..8].try_into().unwrap();
+        let (header_len, size) = match u32::from_be_bytes(header[..4].try_into().unwrap()) {
+            1 => {
+                file.read_exact(&mut header[8..])?;
+                (16, u64::from_be_bytes(header[8..].try_into().unwrap()))
+            }
+            size => (8, size as u64),
+        };
+        if size < header_len || offset + size > file_len {
+            return Err(RemuxError::Invalid(format!(
+                "written box {} is truncated",
+                String::from_utf8_lossy(&kind)
+            )));
+        }
+        match &kind {
+            b"moov" => {
+                let mut payload = vec![0; (size - header_len) as usize];
+                file.read_exact(&mut payload)?;
+                moov = Some(Mp4Box::parse_all(&payload)?);
+            }
+            b"mdat" => mdat_found = size - header_len == mdat_len,
+            _ => {}
+        }
+        offset += size;
+    }
+
+    let moov = moov.ok_or_else(|| RemuxError::Invalid("written file has no moov".to_string()))?;
+    if !mdat_found {
+        return Err(RemuxError::Invalid(
+            "written mdat has the wrong size".to_string(),
+        ));
+    }
+    let mut written_samples = 0;
+    let mut duration = 0.0;
+    for child in moov.iter() {
+        match child.kind() {
+            b"mvhd" => {
+                let payload = child.payload();
+                let (duration_at, len) = if payload.first() == Some(&1) {
+                    (24, 8)
+                } else {
+                    (16, 4)
+                };
+                let mut value = [0u8; 8];
+                if let Some(bytes) = payload.get(duration_at..duration_at + len) {
+                    value[8 - len..].copy_from_slice(bytes);
+                }
+                duration = u64::from_be_bytes(value) as f64 / timescale(payload)?.max(1) as f64;
+            }
+            b"trak" => {
+                if let Some(stsz) = child.find(&[b"mdia", b"minf", b"stbl", b"stsz"]) {
+                    let mut reader = Reader::new(stsz.payload());
+                    reader.skip(8)?;
+                    written_samples += reader.u32()? as u64;
+                }
+            }
+            _ => {}
+        }
+    }
+    if written_samples != samples {
+        return Err(RemuxError::Invalid(format!(
+            "wrote {} samples instead of {}",
+            written_samples, samples
+        )));
+    }
+    if duration <= 0.0 {
+        return Err(RemuxError::Invalid(
+            "written file has no duration".to_string(),
+        ));
+    }
+    Ok(RemuxSummary {
+        duration,
+        samples: written_samples,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mp4::boxes::split_boxes;
+
+    const MEDIA_TIMESCALE: u32 = 90000;
+    const SAMPLE_DURATION: u32 = 3000;
+
+    fn container(kind: &[u8; 4], children: Vec<Mp4Box>) -> Mp4Box {
+        Mp4Box::Container {
+            kind: *kind,
+            children,
+        }
+    }
+
+    fn full(kind: &[u8; 4], version: u8, flags: u32, fields: &[u32]) -> Mp4Box {
+        let mut payload = vec![];
+        push_u32(&mut payload, (version as u32) << 24 | flags);
+        for field in fields {
+            push_u32(&mut payload, *field);
+        }
+        Mp4Box::leaf(kind, payload)
+    }
+
+    fn bytes(boxes: &[Mp4Box]) -> Vec<u8> {
+        let mut out = vec![];
+        for b in boxes {
+            b.write(&mut out);
+        }
+        out
+    }
+
+    /// ffmpeg like init segment : one video track, non-sync samples by default
+    fn init_segment() -> Vec<u8> {
+        let mut mvhd = vec![0, 0, 1000, 0];
+        mvhd.resize(25, 0);
+        let mut tkhd = vec![0, 0, 1, 0, 0];
+        tkhd.resize(20, 0);
+        let stbl = container(
+            b"stbl",
+            vec![
+                full(b"stsd", 0, 0, &[0]),
+                full(b"stts", 0, 0, &[0]),
+                full(b"stsc", 0, 0, &[0]),
+                full(b"stsz", 0, 0, &[0, 0]),
+                full(b"stco", 0, 0, &[0]),
+            ],
+        );
+        let trak = container(
+            b"trak",
+            vec![
+                full(b"tkhd", 0, 3, &tkhd),
+                container(b"edts", vec![full(b"elst", 0, 0, &[1, 0, 0, 0x0001_0000])]),
+                container(
+                    b"mdia",
+                    vec![
+                        full(b"mdhd", 0, 0, &[0, 0, MEDIA_TIMESCALE, 0, 0]),
+                        container(b"minf", vec![stbl]),
+                    ],
+                ),
+            ],
+        );
+        let mvex = container(
+            b"mvex",
+            vec![full(
+                b"trex",
+                0,
+                0,
+                &[1, 1, SAMPLE_DURATION, 0, 0x0001_0000],
+            )],
+        );
+        bytes(&[
+            Mp4Box::leaf(b"ftyp", b"isom\0\0\x02\0".to_vec()),
+            container(b"moov", vec![full(b"mvhd", 0, 0, &mvhd), trak, mvex]),
+        ])
+    }
+
+    /// moof + mdat with one trun : a sync sample first, then the others with `cts_offsets`
+    fn media_segment(sample_sizes: &[u32], cts_offsets: &[i32], fill: u8) -> Vec<u8> {
+        let moof = |data_offset: u32| {
+            // data offset, sample size & composition offset per sample, first sample flags
+            let mut fields = vec![sample_sizes.len() as u32, data_offset, 0];
+            for (size, cts) in sample_sizes.iter().zip(cts_offsets) {
+                fields.extend([*size, *cts as u32]);
+            }
+            container(
+                b"moof",
+                vec![
+                    full(b"mfhd", 0, 0, &[1]),
+                    container(
+                        b"traf",
+                        vec![
+                            full(b"tfhd", 0, 0x02_0000, &[1]),
+                            full(b"trun", 1, 0xA05, &fields),
+                        ],
+                    ),
+                ],
+            )
+        };
+        let size = moof(0).size() as u32;
+        let mut out = bytes(&[moof(size + 8)]);
+        let payload_len: u32 = sample_sizes.iter().sum();
+        push_u32(&mut out, payload_len + 8);
+        out.extend_from_slice(b"mdat");
+        out.extend(std::iter::repeat_n(fill, payload_len as usize));
+        out
+    }
+
+    struct TempDir(String);
+
+    impl TempDir {
+        fn new(name: &str) -> TempDir {
+            let dir =
+                std::env::temp_dir().join(format!("nephtys-mp4-{}-{}", name, std::process::id()));
+            let _ = fs::remove_dir_all(&dir);
+            fs::create_dir_all(&dir).unwrap();
+            TempDir(dir.to_string_lossy().to_string())
+        }
+
+        fn write(&self, name: &str, data: &[u8]) -> String {
+            let path = format!("{}/{}", self.0, name);
+            fs::write(&path, data).unwrap();
+            path
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn u32_at(data: &[u8], offset: usize) -> u32 {
+        u32::from_be_bytes(data[offset..offset + 4].try_into().unwrap())
+    }
+
+    fn table(moov: &Mp4Box, kind: &[u8; 4]) -> Option<Vec<u8>> {
+        moov.find(&[b"trak", b"mdia", b"minf", b"stbl", kind])
+            .map(|b| b.payload().to_vec())
+    }
+
+    /// Remuxes two segments of 3 samples, returns the output & its moov
+    fn remux(dir: &TempDir, cts_offsets: &[i32]) -> (RemuxSummary, Vec<u8>, Mp4Box) {
+        let init = dir.write("init.mp4", &init_segment());
+        let segments = vec![
+            dir.write("0.m4s", &media_segment(&[100, 20, 30], cts_offsets, 0xA0)),
+            dir.write("1.m4s", &media_segment(&[50, 10, 40], cts_offsets, 0xB0)),
+        ];
+        let output = format!("{}/clip.mp4", dir.0);
+        let summary = remux_fragments(&init, &segments, &output).unwrap();
+        assert!(!fs::exists(format!("{}.part", output)).unwrap());
+        let data = fs::read(&output).unwrap();
+        let moov = Mp4Box::parse_all(&data)
+            .unwrap()
+            .into_iter()
+            .find(|b| b.kind() == b"moov")
+            .unwrap();
+        (summary, data, moov)
+    }
+
+    #[test]
+    fn remuxes_into_ftyp_moov_and_64_bits_mdat() {
+        let dir = TempDir::new("layout");
+        let (summary, data, moov) = remux(&dir, &[0, 3000, -3000]);
+        assert_eq!(summary.samples, 6);
+        assert!((summary.duration - 0.2).abs() < 1e-9);
+
+        let boxes = split_boxes(&data).unwrap();
+        let kinds: Vec<&[u8; 4]> = boxes.iter().map(|b| &b.kind).collect();
+        assert_eq!(kinds, vec![b"ftyp", b"moov", b"mdat"]);
+        let mdat = &boxes[2];
+        assert_eq!(u32_at(&data, mdat.offset), 1);
+        assert_eq!(mdat.payload.len(), 250);
+
+        assert!(moov.child(b"mvex").is_none());
+        let kinds: Vec<[u8; 4]> = moov
+            .find(&[b"trak", b"mdia", b"minf", b"stbl"])
+            .unwrap()
+            .children()
+            .iter()
+            .map(|b| *b.kind())
+            .collect();
+        assert_eq!(
+            kinds,
+            [
+                *b"stsd", *b"stts", *b"ctts", *b"stss", *b"stsz", *b"stsc", *b"co64"
+            ]
+        );
+        // The edit list covers the whole track, in the movie timescale
+        let elst = moov.find(&[b"trak", b"edts", b"elst"]).unwrap().payload();
+        assert_eq!(u32_at(elst, 8), 200);
+        assert_eq!(
+            u32_at(
+                moov.find(&[b"trak", b"mdia", b"mdhd"]).unwrap().payload(),
+                16
+            ),
+            18000
+        );
+
+        assert!(check_mp4(&format!("{}/clip.mp4", dir.0), 6, 250).is_ok());
+    }
+
+    #[test]
+    fn chunk_offsets_point_at_the_samples() {
+        let dir = TempDir::new("offsets");
+        let (_, data, moov) = remux(&dir, &[0, 0, 0]);
+        let co64 = table(&moov, b"co64").unwrap();
+        assert_eq!(u32_at(&co64, 4), 2);
+        let first = u64::from_be_bytes(co64[8..16].try_into().unwrap()) as usize;
+        let second = u64::from_be_bytes(co64[16..24].try_into().unwrap()) as usize;
+        assert_eq!(second - first, 150);
+        assert!(data[first..second].iter().all(|&b| b == 0xA0));
+        assert!(data[second..].iter().all(|&b| b == 0xB0));
+        assert_eq!(data.len() - second, 100);
+
+        // One chunk of 3 samples per segment
+        let stsc = table(&moov, b"stsc").unwrap();
+        assert_eq!(
+            &stsc[4..],
+            &[0, 0, 0, 1, 0, 0, 0, 1, 0, 0, 0, 3, 0, 0, 0, 1]
+        );
+        let stsz = table(&moov, b"stsz").unwrap();
+        let sizes: Vec<u32> = (0..6).map(|i| u32_at(&stsz, 12 + i * 4)).collect();
+        assert_eq!(sizes, vec![100, 20, 30, 50, 10, 40]);
+    }
+
+    #[test]
+    fn writes_sync_samples_and_composition_offsets() {
+        let dir = TempDir::new("tables");
+        let (_, _, moov) = remux(&dir, &[0, 3000, -3000]);
+        // First sample of each segment
+        let stss = table(&moov, b"stss").unwrap();
+        assert_eq!(&stss[4..], &[0, 0, 0, 2, 0, 0, 0, 1, 0, 0, 0, 4]);
+        let stts = table(&moov, b"stts").unwrap();
+        assert_eq!(&stts[4..], &[0, 0, 0, 1, 0, 0, 0, 6, 0, 0, 0x0B, 0xB8]);
+        // Negative offsets need a version 1 ctts
+        let ctts = table(&moov, b"ctts").unwrap();
+        assert_eq!(ctts[0], 1);
+        assert_eq!(u32_at(&ctts, 4), 6);
+        assert_eq!(u32_at(&ctts, 20), 3000);
+        assert_eq!(u32_at(&ctts, 28) as i32, -3000);
+
+        let dir = TempDir::new("tables-v0");
+        let (_, _, moov) = remux(&dir, &[0, 3000, 6000]);
+        assert_eq!(table(&moov, b"ctts").unwrap()[0], 0);
+        let dir = TempDir::new("tables-none");
+        let (_, _, moov) = remux(&dir, &[0, 0, 0]);
+        assert!(table(&moov, b"ctts").is_none());
+    }
+
+    #[test]
+    fn check_refuses_truncated_files() {
+        let dir = TempDir::new("truncated");
+        remux(&dir, &[0, 0, 0]);
+        let path = format!("{}/clip.mp4", dir.0);
+        let data = fs::read(&path).unwrap();
+        fs::write(&path, &data[..data.len() - 10]).unwrap();
+        assert!(check_mp4(&path, 6, 250).is_err());
+        fs::write(&path, &data).unwrap();
+        assert!(check_mp4(&path, 7, 250).is_err());
+    }
+
+    #[test]
+    fn refuses_segments_of_unknown_tracks() {
+        let dir = TempDir::new("unknown");
+        let init = dir.write("init.mp4", &init_segment());
+        let mut segment = media_segment(&[10], &[0], 0);
+        // tfhd track id 1 -> 2
+        let tfhd = segment.windows(4).position(|w| w == b"tfhd").unwrap();
+        segment[tfhd + 11] = 2;
+        let segments = vec![dir.write("0.m4s", &segment)];
+        let output = format!("{}/clip.mp4", dir.0);
+        assert!(remux_fragments(&init, &segments, &output).is_err());
+        assert!(!fs::exists(&output).unwrap());
+    }
+}
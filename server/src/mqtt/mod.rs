@@ -0,0 +1,380 @@
+use rumqttc::{Client, Event, LastWill, MqttOptions, Packet, QoS};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::{collections::HashMap, sync::Arc, thread, time::Duration};
+
+use crate::camera::CameraConfig;
+use crate::live::{LiveMessage, LiveSender};
+use crate::movement_detector::DetectorState;
+use crate::store::{EventQuery, EventStore};
+
+/// `[mqtt]` section, publishes the cameras to Home Assistant
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct MqttConfig {
+    pub host: String,
+    #[serde(default = "default_port")]
+    pub port: u16,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub username: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub password: Option<String>,
+    /// Nephtys' own topics : `<topic_prefix>/<camera id>/...`
+    #[serde(default = "default_topic_prefix")]
+    pub topic_prefix: String,
+    /// Home Assistant's discovery prefix
+    #[serde(default = "default_discovery_prefix")]
+    pub discovery_prefix: String,
+    #[serde(default = "default_client_id")]
+    pub client_id: String,
+}
+
+fn default_port() -> u16 {
+    1883
+}
+
+fn default_topic_prefix() -> String {
+    "nephtys".to_string()
+}
+
+fn default_discovery_prefix() -> String {
+    "homeassistant".to_string()
+}
+
+fn default_client_id() -> String {
+    "nephtys".to_string()
+}
+
+impl MqttConfig {
+    pub fn check(&self) -> Result<(), String> {
+        if self.host.is_empty() {
+            return Err("host is empty".to_string());
+        }
+        if self.password.is_some() && self.username.is_none() {
+            return Err("password is set without a username".to_string());
+        }
+        for (name, prefix) in [
+            ("topic_prefix", &self.topic_prefix),
+            ("discovery_prefix", &self.discovery_prefix),
+        ] {
+            if prefix.is_empty() || prefix.contains(['#', '+']) || prefix.ends_with('/') {
+                return Err(format!(
+                    "{} must be a topic without wildcards or trailing '/'",
+                    name
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    fn availability_topic(&self) -> String {
+        format!("{}/status", self.topic_prefix)
+    }
+
+    fn topic(&self, camera_id: &str, name: &str) -> String {
+        format!("{}/{}/{}", self.topic_prefix, camera_id, name)
+    }
+}
+
+// Snapshots are sent as a single message
+const MAX_PACKET_SIZE: usize = 8 * 1024 * 1024;
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+/// Topics & discovery payloads of the cameras
+struct Publisher {
+    client: Client,
+    config: MqttConfig,
+    cameras: Vec<CameraConfig>,
+    store: Arc<EventStore>,
+    detectors: HashMap<String, Arc<DetectorState>>,
+}
+
+impl Publisher {
+    fn publish(&self, topic: String, retain: bool, payload: impl Into<Vec<u8>>) {
+        // try_ so the connection thread never waits on itself
+        if let Err(err) = self
+            .client
+            .try_publish(topic.clone(), QoS::AtLeastOnce, retain, payload)
+        {
+            println!("MQTT: ERROR: Couldn't publish {} : {}", topic, err);
+        }
+    }
+
+    /// Home Assistant config of every entity, sent on each connection
+    fn publish_discovery(&self) {
+        for (topic, payload) in discovery_messages(&self.config, &self.cameras) {
+            self.publish(topic, true, payload);
+        }
+    }
+
+    fn publish_state(&self, camera_id: &str, motion: bool) {
+        self.publish(
+            self.config.topic(camera_id, "motion"),
+            true,
+            if motion { "ON" } else { "OFF" },
+        );
+        if let Some(detector) = self.detectors.get(camera_id)
+            && let Some(jpeg) = detector.snapshot.lock().unwrap().clone()
+        {
+            self.publish(self.config.topic(camera_id, "snapshot"), true, jpeg);
+        }
+        match self.store.count_events(camera_id) {
+            Ok(count) => self.publish(
+                self.config.topic(camera_id, "event_count"),
+                true,
+                count.to_string(),
+            ),
+            Err(err) => println!(
+                "[{}] MQTT: ERROR: Couldn't count the events : {}",
+                camera_id, err
+            ),
+        }
+        let last = self.store.events(&EventQuery {
+            camera_id: Some(camera_id.to_string()),
+            limit: Some(1),
+            ..Default::default()
+        });
+        if let Ok(Some(event)) = last.map(|events| events.into_iter().next()) {
+            self.publish(
+                self.config.topic(camera_id, "last_event"),
+                true,
+                event.start,
+            );
+        }
+    }
+
+    fn on_connected(&self) {
+        println!(
+            "MQTT: connected to {}:{}",
+            self.config.host, self.config.port
+        );
+        self.publish(self.config.availability_topic(), true, "online");
+        self.publish_discovery();
+        for camera in self.cameras.iter() {
+            self.publish_state(&camera.id, false);
+        }
+    }
+
+    fn on_live_message(&self, message: LiveMessage) {
+        match message {
+            LiveMessage::EventStart { camera_id, .. } => self.publish_state(&camera_id, true),
+            LiveMessage::EventEnd { camera_id, .. } => self.publish_state(&camera_id, false),
+            _ => {}
+        }
+    }
+}
+
+/// `(topic, payload)` of the Home Assistant config of every camera's entities
+fn discovery_messages(config: &MqttConfig, cameras: &[CameraConfig]) -> Vec<(String, String)> {
+    let mut messages = vec![];
+    for camera in cameras {
+        let device = json!({
+            "identifiers": [format!("nephtys_{}", camera.id)],
+            "name": camera.name,
+            "manufacturer": "Nephtys",
+        });
+        let entities = [
+            (
+                "binary_sensor",
+                "motion",
+                json!({
+                    "name": "Motion",
+                    "device_class": "motion",
+                    "state_topic": config.topic(&camera.id, "motion"),
+                    "payload_on": "ON",
+                    "payload_off": "OFF",
+                }),
+            ),
+            (
+                "camera",
+                "snapshot",
+                json!({
+                    "name": "Snapshot",
+                    "topic": config.topic(&camera.id, "snapshot"),
+                }),
+            ),
+            (
+                "sensor",
+                "event_count",
+                json!({
+                    "name": "Events",
+                    "state_topic": config.topic(&camera.id, "event_count"),
+                    "state_class": "total_increasing",
+                    "icon": "mdi:motion-sensor",
+                }),
+            ),
+            (
+                "sensor",
+                "last_event",
+                json!({
+                    "name": "Last event",
+                    "device_class": "timestamp",
+                    "state_topic": config.topic(&camera.id, "last_event"),
+                }),
+            ),
+        ];
+        for (component, name, mut entity) in entities {
+            let unique_id = format!("nephtys_{}_{}", camera.id, name);
+            entity["unique_id"] = json!(unique_id);
+            entity["availability_topic"] = json!(config.availability_topic());
+            entity["device"] = device.clone();
+            messages.push((
+                format!(
+                    "{}/{}/{}/config",
+                    config.discovery_prefix, component, unique_id
+                ),
+                entity.to_string(),
+            ));
+        }
+    }
+    messages
+}
+
+/// Connects to the broker and keeps Home Assistant updated with the events of the live channel
+pub fn start_mqtt_publisher(
+    config: MqttConfig,
+    cameras: Vec<CameraConfig>,
+    store: Arc<EventStore>,
+    detectors: HashMap<String, Arc<DetectorState>>,
+    live: &LiveSender,
+) {
+    let mut options = MqttOptions::new(&config.client_id, &config.host, config.port);
+    options.set_keep_alive(Duration::from_secs(30));
+    options.set_max_packet_size(MAX_PACKET_SIZE, MAX_PACKET_SIZE);
+    if let Some(username) = &config.username {
+        options.set_credentials(username, config.password.clone().unwrap_or_default());
+    }
+    options.set_last_will(LastWill::new(
+        config.availability_topic(),
+        "offline",
+        QoS::AtLeastOnce,
+        true,
+    ));
+    // Discovery & state of each camera fit in the queue
+    let (client, mut connection) = Client::new(options, 16 + cameras.len() * 8);
+    let publisher = Arc::new(Publisher {
+        client,
+        config,
+        cameras,
+        store,
+        detectors,
+    });
+
+    let connection_publisher = publisher.clone();
+    thread::spawn(move || {
+        for notification in connection.iter() {
+            match notification {
+                Ok(Event::Incoming(Packet::ConnAck(_))) => connection_publisher.on_connected(),
+                Ok(_) => {}
+                Err(err) => {
+                    println!(
+                        "MQTT: ERROR: connection to {}:{} failed : {}, retrying in {}s",
+                        connection_publisher.config.host,
+                        connection_publisher.config.port,
+                        err,
+                        RECONNECT_DELAY.as_secs()
+                    );
+                    thread::sleep(RECONNECT_DELAY);
+                }
+            }
+        }
+    });
+
+    let mut receiver = live.subscribe();
+    thread::spawn(move || {
+        loop {
+            match receiver.blocking_recv() {
+                Ok(message) => publisher.on_live_message(message),
+                Err(tokio::sync::broadcast::error::RecvError::Lagged(_)) => continue,
+                Err(tokio::sync::broadcast::error::RecvError::Closed) => return,
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::Value;
+
+    fn config() -> MqttConfig {
+        toml::from_str("host = \"localhost\"\ntopic_prefix = \"home/nephtys\"").unwrap()
+    }
+
+    fn entity(messages: &[(String, String)], topic: &str) -> Value {
+        let (_, payload) = messages
+            .iter()
+            .find(|(t, _)| t == topic)
+            .unwrap_or_else(|| panic!("no message on {}", topic));
+        serde_json::from_str(payload).unwrap()
+    }
+
+    #[test]
+    fn announces_every_entity_of_every_camera() {
+        let cameras = [
+            CameraConfig::from_device("garden", "/dev/video0"),
+            CameraConfig::from_device("door", "/dev/video1"),
+        ];
+        let messages = discovery_messages(&config(), &cameras);
+        let topics: Vec<&str> = messages.iter().map(|(topic, _)| topic.as_str()).collect();
+        assert_eq!(
+            topics,
+            [
+                "homeassistant/binary_sensor/nephtys_garden_motion/config",
+                "homeassistant/camera/nephtys_garden_snapshot/config",
+                "homeassistant/sensor/nephtys_garden_event_count/config",
+                "homeassistant/sensor/nephtys_garden_last_event/config",
+                "homeassistant/binary_sensor/nephtys_door_motion/config",
+                "homeassistant/camera/nephtys_door_snapshot/config",
+                "homeassistant/sensor/nephtys_door_event_count/config",
+                "homeassistant/sensor/nephtys_door_last_event/config",
+            ]
+        );
+    }
+
+    #[test]
+    fn entities_point_at_the_state_topics() {
+        let cameras = [CameraConfig::from_device("garden", "/dev/video0")];
+        let messages = discovery_messages(&config(), &cameras);
+
+        let motion = entity(
+            &messages,
+            "homeassistant/binary_sensor/nephtys_garden_motion/config",
+        );
+        assert_eq!(motion["state_topic"], "home/nephtys/garden/motion");
+        assert_eq!(motion["unique_id"], "nephtys_garden_motion");
+        assert_eq!(motion["availability_topic"], "home/nephtys/status");
+        assert_eq!(motion["device"]["identifiers"][0], "nephtys_garden");
+
+        let snapshot = entity(
+            &messages,
+            "homeassistant/camera/nephtys_garden_snapshot/config",
+        );
+        assert_eq!(snapshot["topic"], "home/nephtys/garden/snapshot");
+
+        // Only grows, the janitor's deletions show as resets
+        let count = entity(
+            &messages,
+            "homeassistant/sensor/nephtys_garden_event_count/config",
+        );
+        assert_eq!(count["state_topic"], "home/nephtys/garden/event_count");
+        assert_eq!(count["state_class"], "total_increasing");
+
+        let last = entity(
+            &messages,
+            "homeassistant/sensor/nephtys_garden_last_event/config",
+        );
+        assert_eq!(last["device_class"], "timestamp");
+    }
+
+    #[test]
+    fn rejects_wildcard_prefixes() {
+        assert!(config().check().is_ok());
+        for prefix in ["", "home/#", "home/+/x", "home/"] {
+            let config = MqttConfig {
+                topic_prefix: prefix.to_string(),
+                ..config()
+            };
+            assert!(config.check().is_err(), "{:?}", prefix);
+        }
+    }
+}
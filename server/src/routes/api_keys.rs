@@ -0,0 +1,90 @@
+use std::sync::Mutex;
+
+use actix_web::{HttpResponse, Responder, delete, get, post, web};
+use rand::distr::SampleString;
+use serde::{Deserialize, Serialize};
+
+use crate::AppState;
+use crate::routes::auth::AuthUser;
+use crate::store::api_keys::{ApiKey, ApiScope};
+
+const KEY_PREFIX: &str = "nph_";
+const KEY_LENGTH: usize = 40;
+// Characters of the key kept in clear, to recognize it in the list
+const SHOWN_LENGTH: usize = 8;
+const MAX_NAME_LENGTH: usize = 64;
+
+#[derive(Deserialize)]
+struct NewApiKey {
+    name: String,
+    scopes: Vec<ApiScope>,
+}
+
+#[derive(Serialize)]
+struct CreatedApiKey {
+    #[serde(flatten)]
+    api_key: ApiKey,
+    /// Only returned now, the database keeps its hash
+    key: String,
+}
+
+#[get("/api_keys")] // under /protected scope, admins only
+async fn get_api_keys(app_state: web::Data<Mutex<AppState>>) -> impl Responder {
+    let store = app_state.lock().unwrap().store.clone();
+    match store.api_keys() {
+        Ok(api_keys) => HttpResponse::Ok().json(api_keys),
+        Err(_) => HttpResponse::InternalServerError().body("Couldn't read the API keys"),
+    }
+}
+
+/// Creates a key used by the logged in admin, sent as `Authorization: Bearer <key>`
+#[post("/api_keys")] // under /protected scope, admins only
+async fn create_api_key(
+    app_state: web::Data<Mutex<AppState>>,
+    user: AuthUser,
+    new_key: web::Json<NewApiKey>,
+) -> impl Responder {
+    let name = new_key.name.trim();
+    if name.is_empty() || name.chars().count() > MAX_NAME_LENGTH {
+        return HttpResponse::BadRequest().body(format!(
+            "The name must be 1 to {} characters long",
+            MAX_NAME_LENGTH
+        ));
+    }
+    if new_key.scopes.is_empty() {
+        return HttpResponse::BadRequest().body("The key needs at least one scope");
+    }
+    let key = format!(
+        "{}{}",
+        KEY_PREFIX,
+        rand::distr::Alphanumeric.sample_string(&mut rand::rng(), KEY_LENGTH)
+    );
+    let shown = &key[..KEY_PREFIX.len() + SHOWN_LENGTH];
+    let store = app_state.lock().unwrap().store.clone();
+    match store.create_api_key(user.0.id, name, &key, shown, &new_key.scopes) {
+        Ok(api_key) => {
+            println!(
+                "{} created the API key {} ({:?})",
+                user.0.username, api_key.name, api_key.scopes
+            );
+            HttpResponse::Ok().json(CreatedApiKey { api_key, key })
+        }
+        Err(_) => HttpResponse::InternalServerError().body("Couldn't save the API key"),
+    }
+}
+
+#[delete("/api_keys/{id}")] // under /protected scope, admins only
+async fn revoke_api_key(
+    app_state: web::Data<Mutex<AppState>>,
+    id: web::Path<i64>,
+) -> impl Responder {
+    let store = app_state.lock().unwrap().store.clone();
+    match store.revoke_api_key(*id) {
+        Ok(true) => {
+            println!("revoked the API key {}", id);
+            HttpResponse::Ok().body("OK")
+        }
+        Ok(false) => HttpResponse::NotFound().body("Unknown API key"),
+        Err(_) => HttpResponse::InternalServerError().body("Couldn't revoke the API key"),
+    }
+}
@@ -1,26 +1,72 @@
 use std::{
-    sync::{Mutex},
-    time::{Duration, Instant},
+    future::{Ready, ready},
+    sync::{LazyLock, Mutex},
+    time::Duration,
 };
 
 use actix_web::{
-    Error, HttpResponse, Responder,
+    Error, FromRequest, HttpMessage, HttpRequest, HttpResponse, Responder,
     body::MessageBody,
-    cookie::Cookie,
-    dev::{ServiceRequest, ServiceResponse},
-    error::{ErrorUnauthorized},
+    cookie::{Cookie, SameSite, time},
+    dev::{Payload, ServiceRequest, ServiceResponse},
+    error::{ErrorForbidden, ErrorInternalServerError, ErrorUnauthorized},
     get,
+    http::{
+        Method,
+        header::{self, HeaderMap},
+    },
     middleware::Next,
     post, web,
 };
 use argon2::{
     Argon2, PasswordHash, PasswordHasher, PasswordVerifier,
-    password_hash::{SaltString},
+    password_hash::{SaltString, rand_core::OsRng},
 };
 use rand::distr::SampleString;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 
-use crate::{AppState, write_config};
+use crate::AppState;
+use crate::routes::login_limiter::client_ip;
+use crate::store::EventStore;
+use crate::store::api_keys::ApiScope;
+use crate::store::auth_log::AuthOutcome;
+use crate::store::users::{Role, User};
+
+// Sessions expire this long after the login
+const SESSION_LIFETIME: Duration = Duration::from_secs(60 * 60 * 24 * 31);
+/// Readable by the web UI, which sends it back in the `X-CSRF-Token` header
+pub const CSRF_COOKIE: &str = "csrf_token";
+pub const CSRF_HEADER: &str = "X-CSRF-Token";
+
+/// `[security]` section
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(default)]
+pub struct SecurityConfig {
+    /// Other origins allowed to call the API (CORS), e.g. "https://dashboard.local"
+    pub allowed_origins: Vec<String>,
+    /// Only send the session cookies over HTTPS
+    pub secure_cookies: bool,
+}
+
+impl SecurityConfig {
+    pub fn check(&self) -> Result<(), String> {
+        for origin in self.allowed_origins.iter() {
+            let Some(host) = origin
+                .strip_prefix("http://")
+                .or_else(|| origin.strip_prefix("https://"))
+            else {
+                return Err(format!(
+                    "origin {} must start with http:// or https://",
+                    origin
+                ));
+            };
+            if host.is_empty() || host.contains('/') {
+                return Err(format!("origin {} must not have a path", origin));
+            }
+        }
+        Ok(())
+    }
+}
 
 #[derive(Deserialize)]
 struct AuthInfo {
@@ -28,86 +74,184 @@ struct AuthInfo {
     password: String,
 }
 
+const MAX_USERNAME_LENGTH: usize = 64;
+const MIN_PASSWORD_LENGTH: usize = 8;
+
+pub fn check_username(username: &str) -> Result<(), String> {
+    if username.is_empty() || username.chars().count() > MAX_USERNAME_LENGTH {
+        return Err(format!(
+            "The username must be 1 to {} characters long",
+            MAX_USERNAME_LENGTH
+        ));
+    }
+    if username.trim() != username {
+        return Err("The username can't start or end with spaces".to_string());
+    }
+    Ok(())
+}
+
+pub fn check_password(password: &str) -> Result<(), String> {
+    if password.chars().count() < MIN_PASSWORD_LENGTH {
+        return Err(format!(
+            "The password must be at least {} characters long",
+            MIN_PASSWORD_LENGTH
+        ));
+    }
+    Ok(())
+}
+
+/// Argon2 hash with its own salt, slow on purpose : call it from `web::block`
+pub fn hash_password(password: &str) -> Result<String, argon2::password_hash::Error> {
+    let salt = SaltString::generate(&mut OsRng);
+    Ok(Argon2::default()
+        .hash_password(password.as_bytes(), &salt)?
+        .to_string())
+}
+
+/// Slow on purpose : call it from `web::block`
+pub fn verify_password(password: &str, pass_hash: &str) -> bool {
+    PasswordHash::new(pass_hash).is_ok_and(|hash| {
+        Argon2::default()
+            .verify_password(password.as_bytes(), &hash)
+            .is_ok()
+    })
+}
+
+// Checked against when the username doesn't exist, so it takes as long as a wrong password
+static DUMMY_HASH: LazyLock<String> =
+    LazyLock::new(|| hash_password("dummy password").unwrap_or_default());
+
+/// Creates the first user, an admin
 #[post("/auth/create")]
 async fn create_account(
     app_state: web::Data<Mutex<AppState>>,
     info: web::Json<AuthInfo>,
 ) -> impl Responder {
-    let mut data = app_state.lock().unwrap();
-    if data.config.username != "" || data.config.pass_hash != "" {
+    if let Err(err) = check_username(&info.username).and(check_password(&info.password)) {
+        return HttpResponse::BadRequest().body(err);
+    }
+    let store = app_state.lock().unwrap().store.clone();
+    if !matches!(store.has_users(), Ok(false)) {
         return HttpResponse::Forbidden().body("A user was already created for this instance");
     }
 
-    let mut new_conf = data.config.clone();
-    new_conf.username = info.username.clone();
+    let password = info.password.clone();
+    let Ok(Ok(pass_hash)) = web::block(move || hash_password(&password)).await else {
+        return HttpResponse::InternalServerError().body("Password hash couldn't be generated");
+    };
 
-    let argon = Argon2::default();
-    let salt = &SaltString::from_b64(data.config.salt.as_str());
+    // Held so two first users can't be created at once
+    let data = app_state.lock().unwrap();
+    if !matches!(data.store.has_users(), Ok(false)) {
+        return HttpResponse::Forbidden().body("A user was already created for this instance");
+    }
+    let user = match data
+        .store
+        .create_user(&info.username, &pass_hash, Role::Admin)
+    {
+        Ok(Some(user)) => user,
+        _ => return HttpResponse::InternalServerError().body("Couldn't save your login."),
+    };
+    println!("created the admin user {}", user.username);
 
-    match salt {
-        Ok(salt_string) => {
-            let pass_hash = argon.hash_password(&info.password.as_bytes(), salt_string);
-            match pass_hash {
-                Ok(pass_hash) => new_conf.pass_hash = pass_hash.to_string(),
-                Err(_) => {
-                    return HttpResponse::InternalServerError()
-                        .body("Password hash couldn't be generated");
-                }
-            }
-        }
-        Err(_) => {
-            return HttpResponse::InternalServerError().body("Password salt couldn't be generated");
-        }
+    match new_session(&data, user.id) {
+        Ok(response) => response,
+        Err(_) => HttpResponse::InternalServerError()
+            .body("Account created without a token. Try to log in."),
     }
+}
 
-    if write_config(&new_conf).is_err() {
-        return HttpResponse::InternalServerError().body("Couldn't save your login.");
+#[post("/auth/login")]
+async fn login(
+    app_state: web::Data<Mutex<AppState>>,
+    req: HttpRequest,
+    info: web::Json<AuthInfo>,
+) -> impl Responder {
+    // Not held while hashing, Argon2 is slow on purpose
+    let (store, limiter) = {
+        let data = app_state.lock().unwrap();
+        (data.store.clone(), data.login_limiter.clone())
+    };
+    let ip = client_ip(&req);
+    let ip_key = format!("ip {}", ip);
+    let account_key = format!("user {}", info.username);
+    if let Err(wait) = limiter.try_attempt(&[ip_key.clone(), account_key.clone()]) {
+        if let Err(err) = store.log_auth_attempt(&ip, &info.username, AuthOutcome::LockedOut) {
+            println!("ERROR: Couldn't write the login audit log : {}", err);
+        }
+        let wait = wait.as_secs().max(1);
+        return HttpResponse::TooManyRequests()
+            .insert_header((header::RETRY_AFTER, wait.to_string()))
+            .body(format!("Too many login attempts, retry in {}s", wait));
     }
 
-    data.config = new_conf;
+    let user = match store.user_by_name(&info.username) {
+        Ok(user) => user,
+        Err(_) => return HttpResponse::InternalServerError().body("Couldn't read the users"),
+    };
+    let password = info.password.clone();
+    let (user, pass_hash) = match user {
+        Some((user, pass_hash)) => (Some(user), pass_hash),
+        None => (None, DUMMY_HASH.clone()),
+    };
+    let Ok(password_ok) = web::block(move || verify_password(&password, &pass_hash)).await else {
+        return HttpResponse::InternalServerError().body("Couldn't check the password");
+    };
+    let user = user.filter(|_| password_ok);
 
-    let (token, exp) = generate_token();
-    data.tokens.insert(token.clone(), exp);
-    let mut cookie = Cookie::new("Authorization", token);
-    cookie.set_path("/");
-    let mut response = HttpResponse::Ok().body("OK");
-    match response.add_cookie(&cookie) {
-        Ok(_) => return response,
-        Err(_) => {
-            return HttpResponse::InternalServerError()
-                .body("Account created without a token. Try to log in.");
-        }
+    let outcome = if user.is_some() {
+        AuthOutcome::Success
+    } else {
+        println!("Warning: failed login as \"{}\" from {}", info.username, ip);
+        AuthOutcome::InvalidCredentials
+    };
+    if let Err(err) = store.log_auth_attempt(&ip, &info.username, outcome) {
+        println!("ERROR: Couldn't write the login audit log : {}", err);
+    }
+    let Some(user) = user else {
+        return HttpResponse::Unauthorized().body("Invalid username or password");
+    };
+
+    limiter.reset(&[account_key]);
+    limiter.refund(&ip_key);
+    match new_session(&app_state.lock().unwrap(), user.id) {
+        Ok(response) => response,
+        Err(_) => HttpResponse::InternalServerError()
+            .body("Couldn't save the session... please try again"),
     }
 }
 
-#[post("/auth/login")]
-async fn login(app_state: web::Data<Mutex<AppState>>, info: web::Json<AuthInfo>) -> impl Responder {
-    let mut data = app_state.lock().unwrap();
-    let hash = match PasswordHash::new(data.config.pass_hash.as_str()) {
-        Ok(pw_hash) => pw_hash,
-        Err(_) => {
-            return HttpResponse::InternalServerError()
-                .body("Invalid configuration file on server's side");
-        }
-    };
-    match Argon2::default().verify_password(info.password.as_bytes(), &hash) {
-        Ok(_) => {
-            let (token, exp) = generate_token();
-            data.tokens.insert(token.clone(), exp);
-            let mut cookie = Cookie::new("Authorization", token);
-            cookie.set_path("/");
-
-            let mut response = HttpResponse::Ok().body("OK");
-            match response.add_cookie(&cookie) {
-                Ok(_) => return response,
-                Err(_) => {
-                    return HttpResponse::InternalServerError()
-                        .body("Couldn't add the token cookie... please try again");
+/// Revokes the session of the request's token, which must come with its CSRF token
+#[post("/auth/logout")]
+async fn logout(app_state: web::Data<Mutex<AppState>>, req: HttpRequest) -> impl Responder {
+    let store = app_state.lock().unwrap().store.clone();
+    if let Some(cookie) = req.cookie("Authorization") {
+        match store.session(cookie.value()) {
+            Ok(Some(session)) if !csrf_token_matches(req.headers(), &session.csrf_token) => {
+                return HttpResponse::Forbidden().body("Missing or invalid CSRF token");
+            }
+            Ok(Some(_)) => {
+                if store.revoke_session(cookie.value()).is_err() {
+                    return HttpResponse::InternalServerError().body("Couldn't revoke the session");
                 }
             }
+            // Already expired, only the cookies are left to remove
+            Ok(None) => {}
+            Err(_) => {
+                return HttpResponse::InternalServerError().body("Couldn't check the session");
+            }
         }
-        Err(_) => return HttpResponse::Unauthorized().body("Invalid username or password"),
     }
+    let mut response = HttpResponse::Ok().body("OK");
+    for name in ["Authorization", CSRF_COOKIE] {
+        let mut cookie = Cookie::new(name, "");
+        cookie.set_path("/");
+        cookie.make_removal();
+        if response.add_cookie(&cookie).is_err() {
+            return HttpResponse::InternalServerError().body("Couldn't remove the token cookie");
+        }
+    }
+    response
 }
 
 #[get("/check")] // under /protected scope
@@ -116,6 +260,7 @@ async fn get_check_token() -> impl Responder {
     return HttpResponse::Ok().body("OK");
 }
 
+/// Checks the session cookie, and the CSRF token of state changing requests
 pub async fn check_token_middleware(
     req: ServiceRequest,
     next: Next<impl MessageBody>,
@@ -124,22 +269,408 @@ pub async fn check_token_middleware(
         .app_data::<web::Data<Mutex<AppState>>>()
         .unwrap()
         .clone();
-    let data = app_state.lock().unwrap();
-    match req.cookie("Authorization") {
-        Some(cookie) => {
-            if data.tokens.contains_key(cookie.value()) {
-                return next.call(req).await;
-            } else {
-                return Err(ErrorUnauthorized("Invalid Authorization cookie"));
+    // Not held while the request runs, handlers lock the state too
+    let store = app_state.lock().unwrap().store.clone();
+    let bearer = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.trim_start().split_once(' '))
+        // Auth schemes are case insensitive
+        .filter(|(scheme, _)| scheme.eq_ignore_ascii_case("bearer"))
+        .map(|(_, key)| key.trim().to_string());
+    let user = match bearer {
+        Some(key) => check_api_key(&store, &key, req.method(), req.path())?,
+        None => check_session(&store, &req)?,
+    };
+    if user.role != Role::Admin && requires_admin(req.method(), req.path()) {
+        return Err(ErrorForbidden("Only admins can do this"));
+    }
+    // For the handlers, see `AuthUser`
+    req.extensions_mut().insert(user);
+    next.call(req).await
+}
+
+/// The session cookie's user, with the CSRF token checked for state changing requests
+fn check_session(store: &EventStore, req: &ServiceRequest) -> Result<User, Error> {
+    let Some(cookie) = req.cookie("Authorization") else {
+        return Err(ErrorUnauthorized("Missing Authorization cookie or header"));
+    };
+    let session = match store.session(cookie.value()) {
+        Ok(Some(session)) => session,
+        Ok(None) => return Err(ErrorUnauthorized("Invalid or expired Authorization cookie")),
+        Err(_) => return Err(ErrorInternalServerError("Couldn't check the session")),
+    };
+    if !is_safe_method(req.method()) && !csrf_token_matches(req.headers(), &session.csrf_token) {
+        return Err(ErrorForbidden("Missing or invalid CSRF token"));
+    }
+    Ok(session.user)
+}
+
+fn csrf_token_matches(headers: &HeaderMap, csrf_token: &str) -> bool {
+    let sent = headers
+        .get(CSRF_HEADER)
+        .and_then(|value| value.to_str().ok());
+    !csrf_token.is_empty() && sent == Some(csrf_token)
+}
+
+/// The API key's user, if one of its scopes allows the request.
+/// No CSRF token : browsers never send the header by themselves.
+fn check_api_key(
+    store: &EventStore,
+    key: &str,
+    method: &Method,
+    path: &str,
+) -> Result<User, Error> {
+    let (api_key, user) = match store.use_api_key(key) {
+        Ok(Some(found)) => found,
+        Ok(None) => return Err(ErrorUnauthorized("Invalid or revoked API key")),
+        Err(_) => return Err(ErrorInternalServerError("Couldn't check the API key")),
+    };
+    if !api_key
+        .scopes
+        .iter()
+        .any(|scope| scope_allows(*scope, method, path))
+    {
+        return Err(ErrorForbidden(format!(
+            "The API key {} doesn't have the scope for this",
+            api_key.name
+        )));
+    }
+    Ok(user)
+}
+
+/// Read-only scopes only reach their own routes
+fn scope_allows(scope: ApiScope, method: &Method, path: &str) -> bool {
+    let (paths, prefixes): (&[&str], &[&str]) = match scope {
+        ApiScope::Admin => return true,
+        ApiScope::Events => (
+            &[
+                "/protected/check",
+                "/protected/cameras",
+                "/protected/events",
+                "/protected/live",
+                "/protected/recordings",
+            ],
+            &[
+                "/protected/events/",
+                "/protected/clips/",
+                "/protected/recordings/",
+            ],
+        ),
+        ApiScope::Stream => {
+            if path.starts_with("/protected/cameras/") && path.ends_with("/snapshot") {
+                return is_safe_method(method);
             }
+            (
+                &["/protected/check", "/protected/cameras"],
+                &["/protected/stream/"],
+            )
+        }
+    };
+    is_safe_method(method)
+        && (paths.contains(&path) || prefixes.iter().any(|prefix| path.starts_with(prefix)))
+}
+
+// Every role can change its own password
+const VIEWER_POST_PATHS: [&str; 1] = ["/protected/account/password"];
+
+/// Viewers only watch : anything changing the state, the users & the API keys are for admins
+fn requires_admin(method: &Method, path: &str) -> bool {
+    path.starts_with("/protected/users")
+        || path.starts_with("/protected/api_keys")
+        || (!is_safe_method(method) && !VIEWER_POST_PATHS.contains(&path))
+}
+
+/// The logged in user, for handlers behind `check_token_middleware`
+pub struct AuthUser(pub User);
+
+impl FromRequest for AuthUser {
+    type Error = Error;
+    type Future = Ready<Result<AuthUser, Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        ready(
+            req.extensions()
+                .get::<User>()
+                .cloned()
+                .map(AuthUser)
+                .ok_or_else(|| ErrorUnauthorized("Not logged in")),
+        )
+    }
+}
+
+fn is_safe_method(method: &Method) -> bool {
+    [Method::GET, Method::HEAD, Method::OPTIONS].contains(method)
+}
+
+/// Refuses state changing requests sent by pages of other origins,
+/// the ones in `allowed_origins` and the server's own host aside.
+pub async fn check_origin_middleware(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    // Requests without an Origin don't come from a browser page
+    let origin = req
+        .headers()
+        .get(header::ORIGIN)
+        .and_then(|origin| origin.to_str().ok());
+    if let Some(origin) = origin
+        && !is_safe_method(req.method())
+    {
+        let allowed = req
+            .app_data::<web::Data<Mutex<AppState>>>()
+            .unwrap()
+            .lock()
+            .unwrap()
+            .config
+            .security
+            .allowed_origins
+            .iter()
+            .any(|allowed| allowed == origin);
+        // Behind the web UI's proxy the host is in X-Forwarded-Host
+        let host = req
+            .headers()
+            .get("X-Forwarded-Host")
+            .or_else(|| req.headers().get(header::HOST))
+            .and_then(|host| host.to_str().ok());
+        let same_host = origin
+            .split_once("://")
+            .is_some_and(|(_, origin_host)| Some(origin_host) == host);
+        if !allowed && !same_host {
+            return Err(ErrorForbidden("Cross-origin request refused"));
         }
-        None => return Err(ErrorUnauthorized("Missing Authorization cookie")),
     }
+    next.call(req).await
 }
 
-fn generate_token() -> (String, Instant) {
-    let mut exp = Instant::now();
-    exp += Duration::from_secs(60 * 60 * 24 * 31); // Now + 31 days
+/// Saves a new session of the user and returns the response setting its cookies
+fn new_session(data: &AppState, user_id: i64) -> Result<HttpResponse, ()> {
     let token = rand::distr::Alphanumeric.sample_string(&mut rand::rng(), 32);
-    return (token, exp);
-}
\ No newline at end of file
+    let csrf_token = rand::distr::Alphanumeric.sample_string(&mut rand::rng(), 32);
+    data.store
+        .create_session(&token, &csrf_token, user_id, SESSION_LIFETIME)
+        .map_err(|_| ())?;
+
+    let max_age = time::Duration::seconds(SESSION_LIFETIME.as_secs() as i64);
+    let secure = data.config.security.secure_cookies;
+    let session_cookie = Cookie::build("Authorization", token)
+        .path("/")
+        .http_only(true)
+        .same_site(SameSite::Strict)
+        .secure(secure)
+        .max_age(max_age)
+        .finish();
+    // Not HttpOnly : pages of other origins can't read it, the web UI can
+    let csrf_cookie = Cookie::build(CSRF_COOKIE, csrf_token)
+        .path("/")
+        .same_site(SameSite::Strict)
+        .secure(secure)
+        .max_age(max_age)
+        .finish();
+    let mut response = HttpResponse::Ok().body("OK");
+    response.add_cookie(&session_cookie).map_err(|_| ())?;
+    response.add_cookie(&csrf_cookie).map_err(|_| ())?;
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, sync::Arc};
+
+    use actix_web::{
+        App,
+        http::StatusCode,
+        middleware::from_fn,
+        test::{self, TestRequest},
+    };
+
+    use super::*;
+    use crate::live::LiveSender;
+    use crate::routes::login_limiter::LoginLimiter;
+
+    fn app_state(store: Arc<EventStore>) -> web::Data<Mutex<AppState>> {
+        web::Data::new(Mutex::new(AppState {
+            config: toml::from_str("port = 8080").unwrap(),
+            streams: Default::default(),
+            detectors: HashMap::new(),
+            store,
+            live: LiveSender::new(),
+            login_limiter: Arc::new(LoginLimiter::default()),
+        }))
+    }
+
+    fn create_user(store: &EventStore, username: &str, role: Role) -> User {
+        store.create_user(username, "hash", role).unwrap().unwrap()
+    }
+
+    /// Status of a request to a /protected route behind `check_token_middleware`
+    async fn status(store: &Arc<EventStore>, request: TestRequest) -> StatusCode {
+        let app = test::init_service(
+            App::new().app_data(app_state(store.clone())).service(
+                web::scope("/protected")
+                    .wrap(from_fn(check_token_middleware))
+                    .default_service(web::to(HttpResponse::Ok)),
+            ),
+        )
+        .await;
+        match test::try_call_service(&app, request.to_request()).await {
+            Ok(response) => response.status(),
+            Err(err) => err.as_response_error().status_code(),
+        }
+    }
+
+    fn bearer(request: TestRequest, key: &str) -> TestRequest {
+        request.insert_header((header::AUTHORIZATION, format!("Bearer {}", key)))
+    }
+
+    #[test]
+    fn read_only_scopes_stay_on_their_routes() {
+        for scope in [ApiScope::Events, ApiScope::Stream] {
+            assert!(scope_allows(scope, &Method::GET, "/protected/check"));
+            assert!(!scope_allows(scope, &Method::GET, "/protected/users"));
+            assert!(!scope_allows(scope, &Method::GET, "/protected/api_keys"));
+            assert!(!scope_allows(scope, &Method::POST, "/protected/cameras"));
+            assert!(!scope_allows(scope, &Method::DELETE, "/protected/events/1"));
+        }
+        assert!(scope_allows(
+            ApiScope::Events,
+            &Method::GET,
+            "/protected/clips/1.mp4"
+        ));
+        assert!(!scope_allows(
+            ApiScope::Stream,
+            &Method::GET,
+            "/protected/events"
+        ));
+        assert!(scope_allows(
+            ApiScope::Stream,
+            &Method::GET,
+            "/protected/cameras/cam/snapshot"
+        ));
+        assert!(!scope_allows(
+            ApiScope::Stream,
+            &Method::POST,
+            "/protected/cameras/cam/snapshot"
+        ));
+        assert!(scope_allows(
+            ApiScope::Admin,
+            &Method::DELETE,
+            "/protected/users/2"
+        ));
+    }
+
+    #[actix_web::test]
+    async fn scoped_and_viewer_keys_are_refused_on_admin_routes() {
+        let store = Arc::new(EventStore::open(":memory:").unwrap());
+        let admin = create_user(&store, "admin", Role::Admin);
+        let viewer = create_user(&store, "viewer", Role::Viewer);
+        store
+            .create_api_key(
+                admin.id,
+                "events",
+                "events-key",
+                "events",
+                &[ApiScope::Events],
+            )
+            .unwrap();
+        store
+            .create_api_key(
+                viewer.id,
+                "viewer",
+                "viewer-key",
+                "viewer",
+                &[ApiScope::Admin],
+            )
+            .unwrap();
+
+        let get_events = || TestRequest::get().uri("/protected/events");
+        let get_users = || TestRequest::get().uri("/protected/users");
+        assert_eq!(
+            status(&store, bearer(get_events(), "events-key")).await,
+            StatusCode::OK
+        );
+        assert_eq!(
+            status(&store, bearer(get_users(), "events-key")).await,
+            StatusCode::FORBIDDEN
+        );
+        // The admin scope doesn't give a viewer more than their role
+        assert_eq!(
+            status(&store, bearer(get_events(), "viewer-key")).await,
+            StatusCode::OK
+        );
+        assert_eq!(
+            status(&store, bearer(get_users(), "viewer-key")).await,
+            StatusCode::FORBIDDEN
+        );
+        let delete_event = TestRequest::delete().uri("/protected/events/1");
+        assert_eq!(
+            status(&store, bearer(delete_event, "viewer-key")).await,
+            StatusCode::FORBIDDEN
+        );
+    }
+
+    #[actix_web::test]
+    async fn bearer_scheme_is_case_insensitive() {
+        let store = Arc::new(EventStore::open(":memory:").unwrap());
+        let admin = create_user(&store, "admin", Role::Admin);
+        store
+            .create_api_key(admin.id, "key", "secret-key", "secret", &[ApiScope::Admin])
+            .unwrap();
+        for scheme in ["Bearer", "bearer", "BEARER"] {
+            let request = TestRequest::post()
+                .uri("/protected/cameras")
+                .insert_header((header::AUTHORIZATION, format!("{} secret-key", scheme)));
+            assert_eq!(status(&store, request).await, StatusCode::OK, "{}", scheme);
+        }
+        let basic = TestRequest::get()
+            .uri("/protected/check")
+            .insert_header((header::AUTHORIZATION, "Basic secret-key"));
+        assert_eq!(status(&store, basic).await, StatusCode::UNAUTHORIZED);
+    }
+
+    #[actix_web::test]
+    async fn revoked_keys_are_refused() {
+        let store = Arc::new(EventStore::open(":memory:").unwrap());
+        let admin = create_user(&store, "admin", Role::Admin);
+        let api_key = store
+            .create_api_key(admin.id, "key", "secret-key", "secret", &[ApiScope::Admin])
+            .unwrap();
+        let check = || bearer(TestRequest::get().uri("/protected/check"), "secret-key");
+        assert_eq!(status(&store, check()).await, StatusCode::OK);
+
+        assert!(store.revoke_api_key(api_key.id).unwrap());
+        assert!(store.use_api_key("secret-key").unwrap().is_none());
+        assert_eq!(status(&store, check()).await, StatusCode::UNAUTHORIZED);
+    }
+
+    #[actix_web::test]
+    async fn only_valid_keys_skip_the_csrf_token() {
+        let store = Arc::new(EventStore::open(":memory:").unwrap());
+        let admin = create_user(&store, "admin", Role::Admin);
+        store
+            .create_api_key(admin.id, "key", "secret-key", "secret", &[ApiScope::Admin])
+            .unwrap();
+        store
+            .create_session("session", "csrf", admin.id, SESSION_LIFETIME)
+            .unwrap();
+        let post = || {
+            TestRequest::post()
+                .uri("/protected/cameras")
+                .cookie(Cookie::new("Authorization", "session"))
+        };
+
+        assert_eq!(
+            status(&store, bearer(post(), "secret-key")).await,
+            StatusCode::OK
+        );
+        assert_eq!(status(&store, post()).await, StatusCode::FORBIDDEN);
+        let with_csrf = || post().insert_header((CSRF_HEADER, "csrf"));
+        assert_eq!(status(&store, with_csrf()).await, StatusCode::OK);
+        // A wrong key never falls back to the cookie, even with its CSRF token
+        assert_eq!(
+            status(&store, bearer(with_csrf(), "wrong-key")).await,
+            StatusCode::UNAUTHORIZED
+        );
+    }
+}
@@ -0,0 +1,150 @@
+use std::sync::Mutex;
+
+use actix_web::{HttpResponse, Responder, get, post, web};
+use serde::Serialize;
+
+use crate::{
+    AppState,
+    camera::probe::list_devices,
+    camera::supervisor::StreamStatus,
+    movement_detector::{
+        settings::DetectionConfig,
+        zones::{DetectionZone, check_zones},
+    },
+    write_config,
+};
+
+#[derive(Serialize)]
+struct CameraInfo {
+    id: String,
+    name: String,
+    stream: Option<StreamStatus>,
+}
+
+#[get("/cameras")] // under /protected scope
+async fn get_cameras(app_state: web::Data<Mutex<AppState>>) -> impl Responder {
+    let data = app_state.lock().unwrap();
+    let streams = data.streams.lock().unwrap();
+    let cameras: Vec<CameraInfo> = data
+        .config
+        .cameras
+        .iter()
+        .map(|camera| CameraInfo {
+            id: camera.id.clone(),
+            name: camera.name.clone(),
+            stream: streams.get(&camera.id).cloned(),
+        })
+        .collect();
+    HttpResponse::Ok().json(cameras)
+}
+
+#[get("/devices")] // under /protected scope
+async fn get_devices() -> impl Responder {
+    match web::block(list_devices).await {
+        Ok(devices) => HttpResponse::Ok().json(devices),
+        Err(_) => HttpResponse::InternalServerError().body("Couldn't probe the video devices"),
+    }
+}
+
+#[get("/cameras/{id}/zones")] // under /protected scope
+async fn get_zones(
+    app_state: web::Data<Mutex<AppState>>,
+    path: web::Path<String>,
+) -> impl Responder {
+    let data = app_state.lock().unwrap();
+    match data.detectors.get(path.as_str()) {
+        Some(detector) => HttpResponse::Ok().json(detector.zones.lock().unwrap().clone()),
+        None => HttpResponse::NotFound().body("Unknown camera"),
+    }
+}
+
+#[post("/cameras/{id}/zones")] // under /protected scope
+async fn set_zones(
+    app_state: web::Data<Mutex<AppState>>,
+    path: web::Path<String>,
+    zones: web::Json<Vec<DetectionZone>>,
+) -> impl Responder {
+    let mut data = app_state.lock().unwrap();
+    let camera_id = path.into_inner();
+    if let Err(err) = check_zones(&zones) {
+        return HttpResponse::BadRequest().body(err);
+    }
+
+    let mut new_conf = data.config.clone();
+    match new_conf
+        .cameras
+        .iter_mut()
+        .find(|camera| camera.id == camera_id)
+    {
+        Some(camera) => camera.zones = zones.clone(),
+        None => return HttpResponse::NotFound().body("Unknown camera"),
+    }
+    if write_config(&new_conf).is_err() {
+        return HttpResponse::InternalServerError().body("Couldn't save the zones.");
+    }
+    data.config = new_conf;
+
+    if let Some(detector) = data.detectors.get(&camera_id) {
+        *detector.zones.lock().unwrap() = zones.into_inner();
+    }
+    HttpResponse::Ok().body("OK")
+}
+
+#[get("/cameras/{id}/snapshot")] // under /protected scope
+async fn get_snapshot(
+    app_state: web::Data<Mutex<AppState>>,
+    path: web::Path<String>,
+) -> impl Responder {
+    let data = app_state.lock().unwrap();
+    let Some(detector) = data.detectors.get(path.as_str()) else {
+        return HttpResponse::NotFound().body("Unknown camera");
+    };
+    match detector.snapshot.lock().unwrap().clone() {
+        Some(jpeg) => HttpResponse::Ok().content_type("image/jpeg").body(jpeg),
+        None => HttpResponse::ServiceUnavailable().body("No frame received from this camera yet"),
+    }
+}
+
+#[get("/cameras/{id}/detection")] // under /protected scope
+async fn get_detection(
+    app_state: web::Data<Mutex<AppState>>,
+    path: web::Path<String>,
+) -> impl Responder {
+    let data = app_state.lock().unwrap();
+    match data.detectors.get(path.as_str()) {
+        Some(detector) => HttpResponse::Ok().json(detector.settings.lock().unwrap().clone()),
+        None => HttpResponse::NotFound().body("Unknown camera"),
+    }
+}
+
+#[post("/cameras/{id}/detection")] // under /protected scope
+async fn set_detection(
+    app_state: web::Data<Mutex<AppState>>,
+    path: web::Path<String>,
+    settings: web::Json<DetectionConfig>,
+) -> impl Responder {
+    let mut data = app_state.lock().unwrap();
+    let camera_id = path.into_inner();
+    if let Err(err) = settings.check() {
+        return HttpResponse::BadRequest().body(err);
+    }
+
+    let mut new_conf = data.config.clone();
+    match new_conf
+        .cameras
+        .iter_mut()
+        .find(|camera| camera.id == camera_id)
+    {
+        Some(camera) => camera.detection = settings.clone(),
+        None => return HttpResponse::NotFound().body("Unknown camera"),
+    }
+    if write_config(&new_conf).is_err() {
+        return HttpResponse::InternalServerError().body("Couldn't save the detection settings.");
+    }
+    data.config = new_conf;
+
+    if let Some(detector) = data.detectors.get(&camera_id) {
+        *detector.settings.lock().unwrap() = settings.into_inner();
+    }
+    HttpResponse::Ok().body("OK")
+}
@@ -0,0 +1,155 @@
+use std::sync::Mutex;
+
+use actix_web::{HttpResponse, Responder, delete, get, web};
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    AppState,
+    movement_detector::{MovementEvent, janitor::remove_clip},
+    store::{EventCursor, EventQuery},
+};
+
+const DEFAULT_LIMIT: u32 = 50;
+const MAX_LIMIT: u32 = 500;
+
+#[derive(Deserialize)]
+struct EventsParams {
+    camera: Option<String>,
+    /// RFC 3339 dates
+    from: Option<String>,
+    to: Option<String>,
+    label: Option<String>,
+    limit: Option<u32>,
+    /// `next_cursor` of the previous page
+    cursor: Option<String>,
+}
+
+#[derive(Serialize)]
+struct EventsPage {
+    events: Vec<MovementEvent>,
+    /// Set when more events match, to get the next page
+    next_cursor: Option<String>,
+}
+
+fn parse_date(date: &Option<String>, name: &str) -> Result<Option<DateTime<Local>>, String> {
+    match date {
+        Some(date) => DateTime::parse_from_rfc3339(date)
+            .map(|date| Some(date.with_timezone(&Local)))
+            .map_err(|_| format!("{} must be an RFC 3339 date", name)),
+        None => Ok(None),
+    }
+}
+
+fn parse_params(params: EventsParams) -> Result<EventQuery, String> {
+    let limit = params.limit.unwrap_or(DEFAULT_LIMIT);
+    if !(1..=MAX_LIMIT).contains(&limit) {
+        return Err(format!("limit must be between 1 and {}", MAX_LIMIT));
+    }
+    let after = match &params.cursor {
+        Some(cursor) => Some(EventCursor::decode(cursor).ok_or("Invalid cursor")?),
+        None => None,
+    };
+    Ok(EventQuery {
+        from: parse_date(&params.from, "from")?,
+        to: parse_date(&params.to, "to")?,
+        camera_id: params.camera,
+        label: params.label,
+        // One more to know if there is a next page
+        limit: Some(limit + 1),
+        after,
+    })
+}
+
+#[get("/events")] // under /protected scope
+async fn get_events(
+    app_state: web::Data<Mutex<AppState>>,
+    params: web::Query<EventsParams>,
+) -> impl Responder {
+    let query = match parse_params(params.into_inner()) {
+        Ok(query) => query,
+        Err(err) => return HttpResponse::BadRequest().body(err),
+    };
+    let store = app_state.lock().unwrap().store.clone();
+    let limit = query.limit.unwrap_or_default() as usize - 1;
+    match web::block(move || store.events(&query)).await {
+        Ok(Ok(mut events)) => {
+            let next_cursor = if events.len() > limit {
+                events.truncate(limit);
+                events
+                    .last()
+                    .and_then(EventCursor::of)
+                    .map(|cursor| cursor.encode())
+            } else {
+                None
+            };
+            HttpResponse::Ok().json(EventsPage {
+                events,
+                next_cursor,
+            })
+        }
+        _ => HttpResponse::InternalServerError().body("Couldn't read the events"),
+    }
+}
+
+#[get("/events/{id}")] // under /protected scope
+async fn get_event(app_state: web::Data<Mutex<AppState>>, path: web::Path<i64>) -> impl Responder {
+    let store = app_state.lock().unwrap().store.clone();
+    let id = path.into_inner();
+    match web::block(move || store.event(id)).await {
+        Ok(Ok(Some(event))) => HttpResponse::Ok().json(event),
+        Ok(Ok(None)) => HttpResponse::NotFound().body("Unknown event"),
+        _ => HttpResponse::InternalServerError().body("Couldn't read the event"),
+    }
+}
+
+enum Deletion {
+    Deleted,
+    UnknownEvent,
+    /// The clip is still being generated
+    Busy,
+    ClipError,
+}
+
+/// Deletes the event and its clip
+#[delete("/events/{id}")] // under /protected scope
+async fn delete_event(
+    app_state: web::Data<Mutex<AppState>>,
+    path: web::Path<i64>,
+) -> impl Responder {
+    let store = app_state.lock().unwrap().store.clone();
+    let id = path.into_inner();
+    let result = web::block(move || {
+        let Some(event) = store.event(id)? else {
+            return Ok(Deletion::UnknownEvent);
+        };
+        if event.duration.is_none() && event.error.is_none() {
+            return Ok(Deletion::Busy);
+        }
+        if let Err(err) = remove_clip(&event.camera_id, &event.filename) {
+            println!(
+                "[{}] ERROR: Couldn't remove clip {} : {}",
+                event.camera_id, event.filename, err
+            );
+            return Ok(Deletion::ClipError);
+        }
+        store.delete_event(id)?;
+        println!(
+            "[{}] removed clip {} (deleted through the API)",
+            event.camera_id, event.filename
+        );
+        Ok::<_, rusqlite::Error>(Deletion::Deleted)
+    })
+    .await;
+    match result {
+        Ok(Ok(Deletion::Deleted)) => HttpResponse::Ok().body("OK"),
+        Ok(Ok(Deletion::UnknownEvent)) => HttpResponse::NotFound().body("Unknown event"),
+        Ok(Ok(Deletion::Busy)) => {
+            HttpResponse::Conflict().body("The clip is still being generated")
+        }
+        Ok(Ok(Deletion::ClipError)) => {
+            HttpResponse::InternalServerError().body("Couldn't delete the clip")
+        }
+        _ => HttpResponse::InternalServerError().body("Couldn't delete the event"),
+    }
+}
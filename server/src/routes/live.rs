@@ -0,0 +1,35 @@
+use std::{sync::Mutex, time::Duration};
+
+use actix_web::{HttpResponse, Responder, get, web};
+use futures_util::stream;
+use tokio::{sync::broadcast::error::RecvError, time::timeout};
+
+use crate::AppState;
+
+// Comment line sent when nothing happened, so proxies keep the connection open
+const KEEP_ALIVE: Duration = Duration::from_secs(15);
+
+/// Server-Sent Events stream of the `LiveMessage`s, named after their `type`
+#[get("/live")] // under /protected scope
+async fn get_live(app_state: web::Data<Mutex<AppState>>) -> impl Responder {
+    let receiver = app_state.lock().unwrap().live.subscribe();
+    let events = stream::unfold(receiver, |mut receiver| async move {
+        let chunk = match timeout(KEEP_ALIVE, receiver.recv()).await {
+            Ok(Ok(message)) => match serde_json::to_string(&message) {
+                Ok(data) => format!("event: {}\ndata: {}\n\n", message.kind(), data),
+                Err(_) => String::new(),
+            },
+            // The client was too slow, it can refresh from the events API
+            Ok(Err(RecvError::Lagged(missed))) => {
+                format!("event: lagged\ndata: {}\n\n", missed)
+            }
+            Ok(Err(RecvError::Closed)) => return None,
+            Err(_) => ": keep-alive\n\n".to_string(),
+        };
+        Some((Ok::<_, actix_web::Error>(web::Bytes::from(chunk)), receiver))
+    });
+    HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header(("Cache-Control", "no-cache"))
+        .streaming(events)
+}
@@ -0,0 +1,217 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use actix_web::HttpRequest;
+
+// Attempts allowed before the lockouts start
+const FREE_ATTEMPTS: u32 = 5;
+// First lockout, doubled by each attempt after it
+const BASE_LOCKOUT: Duration = Duration::from_secs(2);
+const MAX_LOCKOUT: Duration = Duration::from_secs(15 * 60);
+// An IP or account is forgotten after this long without attempts
+const FORGET_AFTER: Duration = Duration::from_secs(24 * 60 * 60);
+
+#[derive(Debug)]
+struct Attempts {
+    count: u32,
+    last: Instant,
+    locked_until: Option<Instant>,
+}
+
+/// Throttles the logins per IP and per account, so the password can't be brute-forced
+/// and the Argon2 hashing can't be used to load the CPU.
+#[derive(Debug, Default)]
+pub struct LoginLimiter {
+    attempts: Mutex<HashMap<String, Attempts>>,
+}
+
+impl LoginLimiter {
+    /// Counts an attempt for each key, or returns how long to wait if one of them is locked.
+    /// Counted before checking the password, so parallel requests can't get around it.
+    pub fn try_attempt(&self, keys: &[String]) -> Result<(), Duration> {
+        self.try_attempt_at(keys, Instant::now())
+    }
+
+    fn try_attempt_at(&self, keys: &[String], now: Instant) -> Result<(), Duration> {
+        let mut attempts = self.attempts.lock().unwrap();
+        attempts.retain(|_, attempt| now.duration_since(attempt.last) < FORGET_AFTER);
+
+        let wait = keys
+            .iter()
+            .filter_map(|key| attempts.get(key)?.locked_until)
+            .filter(|until| *until > now)
+            .map(|until| until - now)
+            .max();
+        if let Some(wait) = wait {
+            return Err(wait);
+        }
+
+        for key in keys {
+            let attempt = attempts.entry(key.clone()).or_insert(Attempts {
+                count: 0,
+                last: now,
+                locked_until: None,
+            });
+            attempt.count += 1;
+            attempt.last = now;
+            if attempt.count >= FREE_ATTEMPTS {
+                let doublings = (attempt.count - FREE_ATTEMPTS).min(16);
+                let lockout = BASE_LOCKOUT.saturating_mul(1 << doublings).min(MAX_LOCKOUT);
+                attempt.locked_until = Some(now + lockout);
+                if attempt.count == FREE_ATTEMPTS {
+                    println!(
+                        "Warning: too many login attempts for {}, locking it out",
+                        key
+                    );
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// A successful login clears the account's attempts. The IP's are kept: logging into
+    /// one's own account mustn't unlock an IP that is guessing the passwords of others.
+    pub fn reset(&self, keys: &[String]) {
+        let mut attempts = self.attempts.lock().unwrap();
+        for key in keys {
+            attempts.remove(key);
+        }
+    }
+
+    /// Takes back the attempt of a successful login, so only the failures count against an IP
+    /// and the users behind one address can log in as often as they want.
+    /// The lock it may have started goes too: the previous ones had expired for it to be let in.
+    pub fn refund(&self, key: &str) {
+        let mut attempts = self.attempts.lock().unwrap();
+        if let Some(attempt) = attempts.get_mut(key) {
+            attempt.count = attempt.count.saturating_sub(1);
+            attempt.locked_until = None;
+        }
+    }
+}
+
+/// Address of the client. The server only listens on localhost behind the web UI's proxy,
+/// which appends the address it received the request from to X-Forwarded-For.
+pub fn client_ip(req: &HttpRequest) -> String {
+    let Some(peer) = req.peer_addr().map(|addr| addr.ip()) else {
+        return "unknown".to_string();
+    };
+    if peer.is_loopback()
+        && let Some(forwarded) = req
+            .headers()
+            .get("X-Forwarded-For")
+            .and_then(|forwarded| forwarded.to_str().ok())
+        && let Some(last) = forwarded.rsplit(',').next()
+        && let Ok(ip) = last.trim().parse::<IpAddr>()
+    {
+        return ip.to_string();
+    }
+    peer.to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keys(ip: &str, user: &str) -> [String; 2] {
+        [format!("ip {}", ip), format!("user {}", user)]
+    }
+
+    #[test]
+    fn lockouts_double_after_the_free_attempts() {
+        let limiter = LoginLimiter::default();
+        let keys = keys("10.0.0.1", "admin");
+        let start = Instant::now();
+        for _ in 0..FREE_ATTEMPTS {
+            assert_eq!(limiter.try_attempt_at(&keys, start), Ok(()));
+        }
+        assert_eq!(
+            limiter.try_attempt_at(&keys, start),
+            Err(Duration::from_secs(2))
+        );
+
+        let mut now = start;
+        for lockout in [2, 4, 8, 16] {
+            now += Duration::from_secs(lockout);
+            assert_eq!(limiter.try_attempt_at(&keys, now), Ok(()));
+            assert_eq!(
+                limiter.try_attempt_at(&keys, now),
+                Err(Duration::from_secs(lockout * 2))
+            );
+        }
+    }
+
+    #[test]
+    fn lockouts_stop_growing_at_the_max() {
+        let limiter = LoginLimiter::default();
+        let keys = keys("10.0.0.1", "admin");
+        let mut now = Instant::now();
+        for _ in 0..30 {
+            let _ = limiter.try_attempt_at(&keys, now);
+            now += MAX_LOCKOUT;
+        }
+        assert_eq!(limiter.try_attempt_at(&keys, now), Ok(()));
+        assert_eq!(limiter.try_attempt_at(&keys, now), Err(MAX_LOCKOUT));
+    }
+
+    /// What `login` does after a correct password
+    fn succeed(limiter: &LoginLimiter, keys: &[String; 2]) {
+        limiter.reset(&keys[1..]);
+        limiter.refund(&keys[0]);
+    }
+
+    #[test]
+    fn successful_logins_never_lock_an_ip() {
+        let limiter = LoginLimiter::default();
+        let now = Instant::now();
+        for i in 0..FREE_ATTEMPTS * 4 {
+            let keys = keys("10.0.0.1", &format!("user{}", i % 3));
+            assert_eq!(limiter.try_attempt_at(&keys, now), Ok(()));
+            succeed(&limiter, &keys);
+        }
+        // The failures still count
+        let attacker = keys("10.0.0.1", "admin");
+        for _ in 0..FREE_ATTEMPTS {
+            limiter.try_attempt_at(&attacker, now).unwrap();
+        }
+        assert!(limiter.try_attempt_at(&attacker, now).is_err());
+    }
+
+    #[test]
+    fn reset_only_clears_the_given_keys() {
+        let limiter = LoginLimiter::default();
+        let attacker = keys("10.0.0.1", "admin");
+        let now = Instant::now();
+        for _ in 0..FREE_ATTEMPTS - 1 {
+            limiter.try_attempt_at(&attacker, now).unwrap();
+        }
+        // Logging into another account from the same IP doesn't clear its failures
+        let guest = keys("10.0.0.1", "guest");
+        limiter.try_attempt_at(&guest, now).unwrap();
+        succeed(&limiter, &guest);
+        limiter.try_attempt_at(&attacker, now).unwrap();
+        assert!(limiter.try_attempt_at(&guest, now).is_err());
+        // While its account can still be used from elsewhere
+        assert_eq!(
+            limiter.try_attempt_at(&keys("10.0.0.2", "guest"), now),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn idle_keys_are_forgotten() {
+        let limiter = LoginLimiter::default();
+        let keys = keys("10.0.0.1", "admin");
+        let now = Instant::now();
+        for _ in 0..FREE_ATTEMPTS {
+            limiter.try_attempt_at(&keys, now).unwrap();
+        }
+        let later = now + FORGET_AFTER;
+        assert_eq!(limiter.try_attempt_at(&keys, later), Ok(()));
+        assert_eq!(limiter.try_attempt_at(&keys, later), Ok(()));
+    }
+}
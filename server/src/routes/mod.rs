@@ -1 +1,7 @@
-pub mod auth;
\ No newline at end of file
+pub mod api_keys;
+pub mod auth;
+pub mod cameras;
+pub mod events;
+pub mod live;
+pub mod login_limiter;
+pub mod users;
@@ -0,0 +1,153 @@
+use std::sync::Mutex;
+
+use actix_web::{HttpRequest, HttpResponse, Responder, delete, get, post, web};
+use serde::Deserialize;
+
+use crate::AppState;
+use crate::routes::auth::{
+    AuthUser, check_password, check_username, hash_password, verify_password,
+};
+use crate::store::users::{Role, UserDeletion};
+
+#[derive(Deserialize)]
+struct NewUser {
+    username: String,
+    password: String,
+    role: Role,
+}
+
+#[derive(Deserialize)]
+struct NewPassword {
+    password: String,
+}
+
+#[derive(Deserialize)]
+struct PasswordChange {
+    current_password: String,
+    new_password: String,
+}
+
+/// Runs the Argon2 hashing off the async threads
+async fn hash(password: &str) -> Result<String, HttpResponse> {
+    let password = password.to_string();
+    match web::block(move || hash_password(&password)).await {
+        Ok(Ok(pass_hash)) => Ok(pass_hash),
+        _ => Err(HttpResponse::InternalServerError().body("Password hash couldn't be generated")),
+    }
+}
+
+#[get("/users")] // under /protected scope, admins only
+async fn get_users(app_state: web::Data<Mutex<AppState>>) -> impl Responder {
+    let store = app_state.lock().unwrap().store.clone();
+    match store.users() {
+        Ok(users) => HttpResponse::Ok().json(users),
+        Err(_) => HttpResponse::InternalServerError().body("Couldn't read the users"),
+    }
+}
+
+#[post("/users")] // under /protected scope, admins only
+async fn add_user(
+    app_state: web::Data<Mutex<AppState>>,
+    new_user: web::Json<NewUser>,
+) -> impl Responder {
+    if let Err(err) = check_username(&new_user.username).and(check_password(&new_user.password)) {
+        return HttpResponse::BadRequest().body(err);
+    }
+    let pass_hash = match hash(&new_user.password).await {
+        Ok(pass_hash) => pass_hash,
+        Err(response) => return response,
+    };
+    let store = app_state.lock().unwrap().store.clone();
+    match store.create_user(&new_user.username, &pass_hash, new_user.role) {
+        Ok(Some(user)) => {
+            println!("added the {:?} user {}", user.role, user.username);
+            HttpResponse::Ok().json(user)
+        }
+        Ok(None) => HttpResponse::Conflict().body("This username is taken"),
+        Err(_) => HttpResponse::InternalServerError().body("Couldn't save the user"),
+    }
+}
+
+#[delete("/users/{id}")] // under /protected scope, admins only
+async fn remove_user(app_state: web::Data<Mutex<AppState>>, id: web::Path<i64>) -> impl Responder {
+    let store = app_state.lock().unwrap().store.clone();
+    match store.delete_user(*id) {
+        Ok(UserDeletion::Deleted) => {
+            println!("removed the user {}", id);
+            HttpResponse::Ok().body("OK")
+        }
+        Ok(UserDeletion::NotFound) => HttpResponse::NotFound().body("Unknown user"),
+        Ok(UserDeletion::LastAdmin) => {
+            HttpResponse::Conflict().body("The last admin can't be removed")
+        }
+        Err(_) => HttpResponse::InternalServerError().body("Couldn't remove the user"),
+    }
+}
+
+/// Resets the password of another user, who is logged out everywhere
+#[post("/users/{id}/password")] // under /protected scope, admins only
+async fn set_user_password(
+    app_state: web::Data<Mutex<AppState>>,
+    id: web::Path<i64>,
+    info: web::Json<NewPassword>,
+) -> impl Responder {
+    if let Err(err) = check_password(&info.password) {
+        return HttpResponse::BadRequest().body(err);
+    }
+    let pass_hash = match hash(&info.password).await {
+        Ok(pass_hash) => pass_hash,
+        Err(response) => return response,
+    };
+    let store = app_state.lock().unwrap().store.clone();
+    match store.set_user_password(*id, &pass_hash) {
+        Ok(true) => {}
+        Ok(false) => return HttpResponse::NotFound().body("Unknown user"),
+        Err(_) => return HttpResponse::InternalServerError().body("Couldn't save the password"),
+    }
+    match store.revoke_user_sessions(*id, None) {
+        Ok(_) => HttpResponse::Ok().body("OK"),
+        Err(_) => HttpResponse::InternalServerError()
+            .body("Password changed, but the user's sessions couldn't be revoked"),
+    }
+}
+
+/// The logged in user
+#[get("/account")] // under /protected scope
+async fn get_account(user: AuthUser) -> impl Responder {
+    HttpResponse::Ok().json(user.0)
+}
+
+/// Changes the logged in user's password, its other sessions are revoked
+#[post("/account/password")] // under /protected scope
+async fn change_password(
+    app_state: web::Data<Mutex<AppState>>,
+    req: HttpRequest,
+    user: AuthUser,
+    info: web::Json<PasswordChange>,
+) -> impl Responder {
+    if let Err(err) = check_password(&info.new_password) {
+        return HttpResponse::BadRequest().body(err);
+    }
+    let store = app_state.lock().unwrap().store.clone();
+    let Ok(Some(current_hash)) = store.user_pass_hash(user.0.id) else {
+        return HttpResponse::InternalServerError().body("Couldn't read the user");
+    };
+    let current_password = info.current_password.clone();
+    let verified = web::block(move || verify_password(&current_password, &current_hash)).await;
+    if !matches!(verified, Ok(true)) {
+        return HttpResponse::Forbidden().body("Wrong current password");
+    }
+    let pass_hash = match hash(&info.new_password).await {
+        Ok(pass_hash) => pass_hash,
+        Err(response) => return response,
+    };
+    if !matches!(store.set_user_password(user.0.id, &pass_hash), Ok(true)) {
+        return HttpResponse::InternalServerError().body("Couldn't save the password");
+    }
+    let token = req.cookie("Authorization");
+    match store.revoke_user_sessions(user.0.id, token.as_ref().map(|cookie| cookie.value())) {
+        Ok(_) => HttpResponse::Ok().body("OK"),
+        Err(_) => HttpResponse::InternalServerError()
+            .body("Password changed, but your other sessions couldn't be revoked"),
+    }
+}
@@ -0,0 +1,153 @@
+use chrono::Utc;
+use rusqlite::{OptionalExtension, Row, params};
+use serde::{Deserialize, Serialize};
+
+use crate::store::EventStore;
+use crate::store::sessions::hash_token;
+use crate::store::users::{Role, User};
+
+// last_used_at is written at most this often, streams use the key for every segment
+const LAST_USED_PRECISION_SECS: i64 = 60;
+
+/// What an API key can be used for
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ApiScope {
+    /// Reads the events, their clips & the live updates
+    Events,
+    /// Watches the cameras' streams & snapshots
+    Stream,
+    /// Everything its user can do
+    Admin,
+}
+
+impl ApiScope {
+    fn as_str(&self) -> &'static str {
+        match self {
+            ApiScope::Events => "events",
+            ApiScope::Stream => "stream",
+            ApiScope::Admin => "admin",
+        }
+    }
+
+    fn parse(scope: &str) -> Option<ApiScope> {
+        match scope {
+            "events" => Some(ApiScope::Events),
+            "stream" => Some(ApiScope::Stream),
+            "admin" => Some(ApiScope::Admin),
+            _ => None,
+        }
+    }
+}
+
+/// An API key, without the key itself which is only shown when it's created
+#[derive(Serialize, Clone, Debug)]
+pub struct ApiKey {
+    pub id: i64,
+    pub name: String,
+    /// Start of the key, to recognize it
+    pub prefix: String,
+    pub scopes: Vec<ApiScope>,
+    /// Unix timestamps in seconds
+    pub created_at: i64,
+    pub last_used_at: Option<i64>,
+}
+
+fn api_key_from_row(row: &Row) -> Result<ApiKey, rusqlite::Error> {
+    let scopes: String = row.get("scopes")?;
+    Ok(ApiKey {
+        id: row.get("id")?,
+        name: row.get("name")?,
+        prefix: row.get("prefix")?,
+        scopes: scopes.split(',').filter_map(ApiScope::parse).collect(),
+        created_at: row.get("created_at")?,
+        last_used_at: row.get("last_used_at")?,
+    })
+}
+
+impl EventStore {
+    /// Only the hash of the key is stored
+    pub fn create_api_key(
+        &self,
+        user_id: i64,
+        name: &str,
+        key: &str,
+        prefix: &str,
+        scopes: &[ApiScope],
+    ) -> Result<ApiKey, rusqlite::Error> {
+        let connection = self.connection.lock().unwrap();
+        let created_at = Utc::now().timestamp();
+        let scopes_text = scopes
+            .iter()
+            .map(|scope| scope.as_str())
+            .collect::<Vec<_>>()
+            .join(",");
+        connection.execute(
+            "INSERT INTO api_keys (user_id, name, key_hash, prefix, scopes, created_at)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            params![
+                user_id,
+                name,
+                hash_token(key),
+                prefix,
+                scopes_text,
+                created_at
+            ],
+        )?;
+        Ok(ApiKey {
+            id: connection.last_insert_rowid(),
+            name: name.to_string(),
+            prefix: prefix.to_string(),
+            scopes: scopes.to_vec(),
+            created_at,
+            last_used_at: None,
+        })
+    }
+
+    pub fn api_keys(&self) -> Result<Vec<ApiKey>, rusqlite::Error> {
+        let connection = self.connection.lock().unwrap();
+        let mut statement = connection.prepare("SELECT * FROM api_keys ORDER BY id")?;
+        statement.query_map([], api_key_from_row)?.collect()
+    }
+
+    /// Returns false if it doesn't exist
+    pub fn revoke_api_key(&self, id: i64) -> Result<bool, rusqlite::Error> {
+        Ok(self
+            .connection
+            .lock()
+            .unwrap()
+            .execute("DELETE FROM api_keys WHERE id = ?1", [id])?
+            > 0)
+    }
+
+    /// The key's scopes & user, and marks it as used
+    pub fn use_api_key(&self, key: &str) -> Result<Option<(ApiKey, User)>, rusqlite::Error> {
+        let connection = self.connection.lock().unwrap();
+        let found = connection
+            .query_row(
+                "SELECT api_keys.*, users.username, users.role, users.created_at AS user_created_at
+                FROM api_keys JOIN users ON users.id = api_keys.user_id
+                WHERE api_keys.key_hash = ?1",
+                [hash_token(key)],
+                |row| {
+                    let user = User {
+                        id: row.get("user_id")?,
+                        username: row.get("username")?,
+                        role: Role::parse(&row.get::<_, String>("role")?),
+                        created_at: row.get("user_created_at")?,
+                    };
+                    Ok((api_key_from_row(row)?, user))
+                },
+            )
+            .optional()?;
+        if let Some((api_key, _)) = &found {
+            let now = Utc::now().timestamp();
+            connection.execute(
+                "UPDATE api_keys SET last_used_at = ?1
+                WHERE id = ?2 AND (last_used_at IS NULL OR last_used_at <= ?3)",
+                params![now, api_key.id, now - LAST_USED_PRECISION_SECS],
+            )?;
+        }
+        Ok(found)
+    }
+}
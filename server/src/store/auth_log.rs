@@ -0,0 +1,54 @@
+use chrono::{TimeDelta, Utc};
+use rusqlite::params;
+
+use crate::store::EventStore;
+
+// Entries older than this are deleted with the expired sessions
+pub const AUTH_LOG_MAX_AGE: TimeDelta = TimeDelta::days(90);
+
+/// Result of a login attempt
+#[derive(Clone, Copy, Debug)]
+pub enum AuthOutcome {
+    Success,
+    InvalidCredentials,
+    /// Refused without checking the password, too many attempts
+    LockedOut,
+}
+
+impl AuthOutcome {
+    fn as_str(&self) -> &'static str {
+        match self {
+            AuthOutcome::Success => "success",
+            AuthOutcome::InvalidCredentials => "invalid_credentials",
+            AuthOutcome::LockedOut => "locked_out",
+        }
+    }
+}
+
+impl EventStore {
+    pub fn log_auth_attempt(
+        &self,
+        ip: &str,
+        username: &str,
+        outcome: AuthOutcome,
+    ) -> Result<(), rusqlite::Error> {
+        self.connection.lock().unwrap().execute(
+            "INSERT INTO auth_log (time_ms, ip, username, outcome) VALUES (?1, ?2, ?3, ?4)",
+            params![
+                Utc::now().timestamp_millis(),
+                ip,
+                username,
+                outcome.as_str()
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// Returns how many entries were deleted
+    pub fn delete_old_auth_log(&self) -> Result<usize, rusqlite::Error> {
+        self.connection.lock().unwrap().execute(
+            "DELETE FROM auth_log WHERE time_ms <= ?1",
+            [(Utc::now() - AUTH_LOG_MAX_AGE).timestamp_millis()],
+        )
+    }
+}
@@ -0,0 +1,104 @@
+use rusqlite::Connection;
+
+/// Applied in order, the database's `user_version` is the number of migrations already run.
+/// Never edit a released migration, add a new one.
+const MIGRATIONS: [&str; 6] = [
+    // 1 : initial schema
+    "CREATE TABLE cameras (
+        id TEXT PRIMARY KEY,
+        name TEXT NOT NULL
+    );
+    CREATE TABLE events (
+        id INTEGER PRIMARY KEY,
+        camera_id TEXT NOT NULL REFERENCES cameras (id),
+        filename TEXT NOT NULL UNIQUE,
+        start TEXT NOT NULL,
+        end TEXT NOT NULL,
+        start_ms INTEGER,
+        end_ms INTEGER,
+        pre_roll REAL NOT NULL DEFAULT 0,
+        duration REAL,
+        error TEXT
+    );
+    CREATE INDEX events_camera_start ON events (camera_id, start_ms);
+    CREATE INDEX events_start ON events (start_ms);
+    CREATE TABLE event_objects (
+        event_id INTEGER NOT NULL REFERENCES events (id) ON DELETE CASCADE,
+        label TEXT NOT NULL,
+        confidence REAL NOT NULL
+    );
+    CREATE INDEX event_objects_label ON event_objects (label, event_id);
+    CREATE INDEX event_objects_event ON event_objects (event_id);
+    CREATE TABLE detections (
+        id INTEGER PRIMARY KEY,
+        event_id INTEGER NOT NULL REFERENCES events (id) ON DELETE CASCADE,
+        timestamp TEXT NOT NULL,
+        timestamp_ms INTEGER,
+        frame_index INTEGER NOT NULL,
+        moving_area REAL NOT NULL,
+        boxes TEXT NOT NULL,
+        labels TEXT
+    );
+    CREATE INDEX detections_event ON detections (event_id);
+    CREATE TABLE recordings (
+        id INTEGER PRIMARY KEY,
+        camera_id TEXT NOT NULL REFERENCES cameras (id),
+        path TEXT NOT NULL UNIQUE,
+        start TEXT NOT NULL,
+        start_ms INTEGER NOT NULL,
+        duration REAL NOT NULL
+    );
+    CREATE INDEX recordings_camera_start ON recordings (camera_id, start_ms);",
+    // 2 : login sessions
+    "CREATE TABLE sessions (
+        token_hash TEXT PRIMARY KEY,
+        created_at INTEGER NOT NULL,
+        expires_at INTEGER NOT NULL
+    );
+    CREATE INDEX sessions_expires ON sessions (expires_at);",
+    // 3 : CSRF token of each session
+    "ALTER TABLE sessions ADD COLUMN csrf_token TEXT NOT NULL DEFAULT '';",
+    // 4 : login audit log
+    "CREATE TABLE auth_log (
+        id INTEGER PRIMARY KEY,
+        time_ms INTEGER NOT NULL,
+        ip TEXT NOT NULL,
+        username TEXT NOT NULL,
+        outcome TEXT NOT NULL
+    );
+    CREATE INDEX auth_log_time ON auth_log (time_ms);",
+    // 5 : user accounts, the sessions of the single user login are dropped
+    "CREATE TABLE users (
+        id INTEGER PRIMARY KEY,
+        username TEXT NOT NULL UNIQUE,
+        pass_hash TEXT NOT NULL,
+        role TEXT NOT NULL,
+        created_at INTEGER NOT NULL
+    );
+    DELETE FROM sessions;
+    ALTER TABLE sessions ADD COLUMN user_id INTEGER REFERENCES users (id) ON DELETE CASCADE;
+    CREATE INDEX sessions_user ON sessions (user_id);",
+    // 6 : API keys of the automation clients
+    "CREATE TABLE api_keys (
+        id INTEGER PRIMARY KEY,
+        user_id INTEGER NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+        name TEXT NOT NULL,
+        key_hash TEXT NOT NULL UNIQUE,
+        prefix TEXT NOT NULL,
+        scopes TEXT NOT NULL,
+        created_at INTEGER NOT NULL,
+        last_used_at INTEGER
+    );",
+];
+
+pub fn migrate(connection: &mut Connection) -> Result<(), rusqlite::Error> {
+    let version: usize = connection.pragma_query_value(None, "user_version", |row| row.get(0))?;
+    for (i, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+        let transaction = connection.transaction()?;
+        transaction.execute_batch(migration)?;
+        transaction.pragma_update(None, "user_version", i + 1)?;
+        transaction.commit()?;
+        println!("migrated the database to version {}", i + 1);
+    }
+    Ok(())
+}
@@ -0,0 +1,376 @@
+use chrono::{DateTime, Local};
+use rusqlite::{Connection, OptionalExtension, Row, params};
+use std::sync::Mutex;
+
+use crate::camera::CameraConfig;
+use crate::movement_detector::MovementEvent;
+use crate::movement_detector::events::DetectionEvent;
+use crate::movement_detector::objects::DetectedObject;
+
+pub mod api_keys;
+pub mod auth_log;
+pub mod migrations;
+pub mod sessions;
+pub mod users;
+
+/// Kept next to the clips it indexes
+pub const DATABASE_PATH: &str = "./static/nephtys.db";
+
+/// SQLite database of the events, their detections & the continuous recordings.
+/// One connection shared by every thread, queries are short.
+#[derive(Debug)]
+pub struct EventStore {
+    connection: Mutex<Connection>,
+}
+
+/// Filters of `EventStore::events`, None matches everything
+#[derive(Default, Debug)]
+pub struct EventQuery {
+    pub camera_id: Option<String>,
+    /// Events still going on at or after this time
+    pub from: Option<DateTime<Local>>,
+    /// Events started at or before this time
+    pub to: Option<DateTime<Local>>,
+    /// Events where this class was recognized
+    pub label: Option<String>,
+    pub limit: Option<u32>,
+    /// Only the events listed after this one, see `EventCursor`
+    pub after: Option<EventCursor>,
+}
+
+/// Position in the event list (latest first) to continue a listing from
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct EventCursor {
+    pub start_ms: i64,
+    pub id: i64,
+}
+
+impl EventCursor {
+    pub fn of(event: &MovementEvent) -> Option<EventCursor> {
+        Some(EventCursor {
+            start_ms: timestamp_ms(&event.start)?,
+            id: event.id?,
+        })
+    }
+
+    /// Opaque to API users, `<start_ms>_<id>`
+    pub fn encode(&self) -> String {
+        format!("{}_{}", self.start_ms, self.id)
+    }
+
+    pub fn decode(cursor: &str) -> Option<EventCursor> {
+        let (start_ms, id) = cursor.split_once('_')?;
+        Some(EventCursor {
+            start_ms: start_ms.parse().ok()?,
+            id: id.parse().ok()?,
+        })
+    }
+}
+
+/// What the janitor needs to know about a clip
+pub struct ClipEntry {
+    pub id: i64,
+    pub filename: String,
+    pub end: String,
+    /// Its video was generated, or failed to be
+    pub finished: bool,
+}
+
+fn timestamp_ms(date: &str) -> Option<i64> {
+    DateTime::parse_from_rfc3339(date)
+        .ok()
+        .map(|date| date.timestamp_millis())
+}
+
+impl EventStore {
+    /// Opens (or creates) the database and brings its schema up to date
+    pub fn open(path: &str) -> Result<EventStore, rusqlite::Error> {
+        let mut connection = Connection::open(path)?;
+        connection.pragma_update(None, "foreign_keys", true)?;
+        connection.pragma_update(None, "journal_mode", "WAL")?;
+        migrations::migrate(&mut connection)?;
+        // Their clip was being generated when the server stopped
+        let interrupted = connection.execute(
+            "UPDATE events SET error = 'Interrupted by a server restart'
+             WHERE duration IS NULL AND error IS NULL",
+            [],
+        )?;
+        if interrupted > 0 {
+            println!(
+                "Warning: {} clips were interrupted by the last shutdown",
+                interrupted
+            );
+        }
+        Ok(EventStore {
+            connection: Mutex::new(connection),
+        })
+    }
+
+    /// Adds the configured cameras, removed ones stay for their events
+    pub fn sync_cameras(&self, cameras: &[CameraConfig]) -> Result<(), rusqlite::Error> {
+        let connection = self.connection.lock().unwrap();
+        for camera in cameras {
+            connection.execute(
+                "INSERT INTO cameras (id, name) VALUES (?1, ?2)
+                 ON CONFLICT (id) DO UPDATE SET name = excluded.name",
+                params![camera.id, camera.name],
+            )?;
+        }
+        Ok(())
+    }
+
+    pub fn has_events(&self, camera_id: &str) -> Result<bool, rusqlite::Error> {
+        self.connection.lock().unwrap().query_row(
+            "SELECT EXISTS (SELECT 1 FROM events WHERE camera_id = ?1)",
+            [camera_id],
+            |row| row.get(0),
+        )
+    }
+
+    pub fn count_events(&self, camera_id: &str) -> Result<i64, rusqlite::Error> {
+        self.connection.lock().unwrap().query_row(
+            "SELECT COUNT(*) FROM events WHERE camera_id = ?1",
+            [camera_id],
+            |row| row.get(0),
+        )
+    }
+
+    /// Saves an event with its objects & detections, returns its id
+    pub fn insert_event(&self, event: &MovementEvent) -> Result<i64, rusqlite::Error> {
+        let mut connection = self.connection.lock().unwrap();
+        let transaction = connection.transaction()?;
+        transaction.execute(
+            "INSERT INTO events (camera_id, filename, start, end, start_ms, end_ms, pre_roll, duration, error)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+            params![
+                event.camera_id,
+                event.filename,
+                event.start,
+                event.end,
+                timestamp_ms(&event.start),
+                timestamp_ms(&event.end),
+                event.pre_roll,
+                event.duration,
+                event.error
+            ],
+        )?;
+        let id = transaction.last_insert_rowid();
+        for object in event.objects.iter() {
+            transaction.execute(
+                "INSERT INTO event_objects (event_id, label, confidence) VALUES (?1, ?2, ?3)",
+                params![id, object.label, object.confidence],
+            )?;
+        }
+        for detection in event.detections.iter() {
+            transaction.execute(
+                "INSERT INTO detections (event_id, timestamp, timestamp_ms, frame_index, moving_area, boxes, labels)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+                params![
+                    id,
+                    detection.timestamp,
+                    timestamp_ms(&detection.timestamp),
+                    detection.frame_index as i64,
+                    detection.moving_area,
+                    serde_json::to_string(&detection.boxes).unwrap_or_default(),
+                    detection
+                        .labels
+                        .as_ref()
+                        .and_then(|labels| serde_json::to_string(labels).ok())
+                ],
+            )?;
+        }
+        transaction.commit()?;
+        Ok(id)
+    }
+
+    /// Result of the clip generation, its duration or why it failed
+    pub fn set_clip_result(
+        &self,
+        filename: &str,
+        result: &Result<f64, String>,
+    ) -> Result<(), rusqlite::Error> {
+        let (duration, error) = match result {
+            Ok(duration) => (Some(*duration), None),
+            Err(err) => (None, Some(err.as_str())),
+        };
+        self.connection.lock().unwrap().execute(
+            "UPDATE events SET duration = ?2, error = ?3 WHERE filename = ?1",
+            params![filename, duration, error],
+        )?;
+        Ok(())
+    }
+
+    /// Matching events, latest first
+    pub fn events(&self, query: &EventQuery) -> Result<Vec<MovementEvent>, rusqlite::Error> {
+        let connection = self.connection.lock().unwrap();
+        let mut statement = connection.prepare_cached(
+            "SELECT id, camera_id, filename, start, end, pre_roll, duration, error FROM events e
+             WHERE (?1 IS NULL OR camera_id = ?1)
+               AND (?2 IS NULL OR end_ms >= ?2)
+               AND (?3 IS NULL OR start_ms <= ?3)
+               AND (?4 IS NULL OR EXISTS (
+                   SELECT 1 FROM event_objects o WHERE o.event_id = e.id AND o.label = ?4))
+               AND (?6 IS NULL OR start_ms < ?6 OR (start_ms = ?6 AND id < ?7))
+             ORDER BY start_ms DESC, id DESC
+             LIMIT ?5",
+        )?;
+        let mut events = statement
+            .query_map(
+                params![
+                    query.camera_id,
+                    query.from.map(|from| from.timestamp_millis()),
+                    query.to.map(|to| to.timestamp_millis()),
+                    query.label,
+                    // Negative means no limit for SQLite
+                    query.limit.map_or(-1, |limit| limit as i64),
+                    query.after.map(|after| after.start_ms),
+                    query.after.map(|after| after.id)
+                ],
+                event_from_row,
+            )?
+            .collect::<Result<Vec<_>, _>>()?;
+        for event in events.iter_mut() {
+            load_event_details(&connection, event)?;
+        }
+        Ok(events)
+    }
+
+    pub fn event(&self, id: i64) -> Result<Option<MovementEvent>, rusqlite::Error> {
+        let connection = self.connection.lock().unwrap();
+        let event = connection
+            .query_row(
+                "SELECT id, camera_id, filename, start, end, pre_roll, duration, error FROM events
+                 WHERE id = ?1",
+                [id],
+                event_from_row,
+            )
+            .optional()?;
+        match event {
+            Some(mut event) => {
+                load_event_details(&connection, &mut event)?;
+                Ok(Some(event))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Every clip of a camera, without the details
+    pub fn clips(&self, camera_id: &str) -> Result<Vec<ClipEntry>, rusqlite::Error> {
+        let connection = self.connection.lock().unwrap();
+        let mut statement = connection.prepare_cached(
+            "SELECT id, filename, end, duration IS NOT NULL OR error IS NOT NULL FROM events
+             WHERE camera_id = ?1",
+        )?;
+        statement
+            .query_map([camera_id], |row| {
+                Ok(ClipEntry {
+                    id: row.get(0)?,
+                    filename: row.get(1)?,
+                    end: row.get(2)?,
+                    finished: row.get(3)?,
+                })
+            })?
+            .collect()
+    }
+
+    pub fn delete_event(&self, id: i64) -> Result<bool, rusqlite::Error> {
+        let deleted = self
+            .connection
+            .lock()
+            .unwrap()
+            .execute("DELETE FROM events WHERE id = ?1", [id])?;
+        Ok(deleted > 0)
+    }
+
+    /// Deletes events along with their objects & detections
+    pub fn delete_events(&self, ids: &[i64]) -> Result<(), rusqlite::Error> {
+        let mut connection = self.connection.lock().unwrap();
+        let transaction = connection.transaction()?;
+        for id in ids {
+            transaction.execute("DELETE FROM events WHERE id = ?1", [id])?;
+        }
+        transaction.commit()
+    }
+
+    pub fn add_recording(
+        &self,
+        camera_id: &str,
+        path: &str,
+        start: DateTime<Local>,
+        duration: f64,
+    ) -> Result<(), rusqlite::Error> {
+        self.connection.lock().unwrap().execute(
+            "INSERT INTO recordings (camera_id, path, start, start_ms, duration) VALUES (?1, ?2, ?3, ?4, ?5)
+             ON CONFLICT (path) DO UPDATE SET duration = excluded.duration",
+            params![
+                camera_id,
+                path,
+                start.to_rfc3339(),
+                start.timestamp_millis(),
+                duration
+            ],
+        )?;
+        Ok(())
+    }
+
+    pub fn delete_recording(&self, path: &str) -> Result<(), rusqlite::Error> {
+        self.connection
+            .lock()
+            .unwrap()
+            .execute("DELETE FROM recordings WHERE path = ?1", [path])?;
+        Ok(())
+    }
+}
+
+fn event_from_row(row: &Row) -> Result<MovementEvent, rusqlite::Error> {
+    Ok(MovementEvent {
+        id: Some(row.get(0)?),
+        camera_id: row.get(1)?,
+        filename: row.get(2)?,
+        start: row.get(3)?,
+        end: row.get(4)?,
+        pre_roll: row.get(5)?,
+        objects: vec![],
+        detections: vec![],
+        duration: row.get(6)?,
+        error: row.get(7)?,
+    })
+}
+
+fn load_event_details(
+    connection: &Connection,
+    event: &mut MovementEvent,
+) -> Result<(), rusqlite::Error> {
+    let id = event.id.unwrap_or_default();
+    let mut objects = connection.prepare_cached(
+        "SELECT label, confidence FROM event_objects WHERE event_id = ?1 ORDER BY confidence DESC",
+    )?;
+    event.objects = objects
+        .query_map([id], |row| {
+            Ok(DetectedObject {
+                label: row.get(0)?,
+                confidence: row.get(1)?,
+            })
+        })?
+        .collect::<Result<_, _>>()?;
+
+    let mut detections = connection.prepare_cached(
+        "SELECT timestamp, frame_index, moving_area, boxes, labels FROM detections
+         WHERE event_id = ?1 ORDER BY id",
+    )?;
+    event.detections = detections
+        .query_map([id], |row| {
+            let boxes: String = row.get(3)?;
+            let labels: Option<String> = row.get(4)?;
+            Ok(DetectionEvent {
+                camera_id: event.camera_id.clone(),
+                timestamp: row.get(0)?,
+                frame_index: row.get::<_, i64>(1)? as u64,
+                moving_area: row.get(2)?,
+                boxes: serde_json::from_str(&boxes).unwrap_or_default(),
+                labels: labels.and_then(|labels| serde_json::from_str(&labels).ok()),
+            })
+        })?
+        .collect::<Result<_, _>>()?;
+    Ok(())
+}
@@ -0,0 +1,111 @@
+use chrono::Utc;
+use rusqlite::{OptionalExtension, params};
+use sha2::{Digest, Sha256};
+use std::{sync::Arc, thread, time::Duration};
+
+use crate::store::EventStore;
+use crate::store::users::{User, user_from_row};
+
+const CLEANUP_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// A valid session and its user
+#[derive(Clone, Debug)]
+pub struct Session {
+    pub user: User,
+    pub csrf_token: String,
+}
+
+/// Only the hash of a token is stored, a leaked database can't be used to log in
+pub fn hash_token(token: &str) -> String {
+    format!("{:x}", Sha256::digest(token.as_bytes()))
+}
+
+impl EventStore {
+    pub fn create_session(
+        &self,
+        token: &str,
+        csrf_token: &str,
+        user_id: i64,
+        lifetime: Duration,
+    ) -> Result<(), rusqlite::Error> {
+        let now = Utc::now().timestamp();
+        self.connection.lock().unwrap().execute(
+            "INSERT INTO sessions (token_hash, created_at, expires_at, csrf_token, user_id)
+            VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![
+                hash_token(token),
+                now,
+                now + lifetime.as_secs() as i64,
+                csrf_token,
+                user_id
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// The token's session, None when it doesn't exist or expired
+    pub fn session(&self, token: &str) -> Result<Option<Session>, rusqlite::Error> {
+        self.connection
+            .lock()
+            .unwrap()
+            .query_row(
+                "SELECT users.*, sessions.csrf_token FROM sessions
+                JOIN users ON users.id = sessions.user_id
+                WHERE sessions.token_hash = ?1 AND sessions.expires_at > ?2",
+                params![hash_token(token), Utc::now().timestamp()],
+                |row| {
+                    Ok(Session {
+                        user: user_from_row(row)?,
+                        csrf_token: row.get("csrf_token")?,
+                    })
+                },
+            )
+            .optional()
+    }
+
+    pub fn revoke_session(&self, token: &str) -> Result<(), rusqlite::Error> {
+        self.connection.lock().unwrap().execute(
+            "DELETE FROM sessions WHERE token_hash = ?1",
+            [hash_token(token)],
+        )?;
+        Ok(())
+    }
+
+    /// Logs the user out everywhere, but from the `except` token's session
+    pub fn revoke_user_sessions(
+        &self,
+        user_id: i64,
+        except: Option<&str>,
+    ) -> Result<(), rusqlite::Error> {
+        self.connection.lock().unwrap().execute(
+            "DELETE FROM sessions WHERE user_id = ?1 AND token_hash IS NOT ?2",
+            params![user_id, except.map(hash_token)],
+        )?;
+        Ok(())
+    }
+
+    /// Returns how many sessions were deleted
+    pub fn delete_expired_sessions(&self) -> Result<usize, rusqlite::Error> {
+        self.connection.lock().unwrap().execute(
+            "DELETE FROM sessions WHERE expires_at <= ?1",
+            [Utc::now().timestamp()],
+        )
+    }
+}
+
+/// Deletes the expired sessions and the old login audit log every hour
+pub fn start_session_cleaner(store: Arc<EventStore>) {
+    thread::spawn(move || {
+        loop {
+            match store.delete_expired_sessions() {
+                Ok(0) => {}
+                Ok(deleted) => println!("removed {} expired sessions", deleted),
+                Err(err) => println!("ERROR: Couldn't remove the expired sessions : {}", err),
+            }
+            if let Err(err) = store.delete_old_auth_log() {
+                println!("ERROR: Couldn't remove the old login audit log : {}", err);
+            }
+            thread::sleep(CLEANUP_INTERVAL);
+        }
+    });
+}
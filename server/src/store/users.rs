@@ -0,0 +1,154 @@
+use chrono::Utc;
+use rusqlite::{OptionalExtension, Row, params};
+use serde::{Deserialize, Serialize};
+
+use crate::store::EventStore;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    /// Changes the settings & manages the users
+    Admin,
+    /// Watches the live video & the clips
+    Viewer,
+}
+
+impl Role {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Role::Admin => "admin",
+            Role::Viewer => "viewer",
+        }
+    }
+
+    pub fn parse(role: &str) -> Role {
+        match role {
+            "admin" => Role::Admin,
+            // Unknown roles get the least rights
+            _ => Role::Viewer,
+        }
+    }
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct User {
+    pub id: i64,
+    pub username: String,
+    pub role: Role,
+    /// Unix timestamp in seconds
+    pub created_at: i64,
+}
+
+pub enum UserDeletion {
+    Deleted,
+    NotFound,
+    LastAdmin,
+}
+
+pub fn user_from_row(row: &Row) -> Result<User, rusqlite::Error> {
+    Ok(User {
+        id: row.get("id")?,
+        username: row.get("username")?,
+        role: Role::parse(&row.get::<_, String>("role")?),
+        created_at: row.get("created_at")?,
+    })
+}
+
+impl EventStore {
+    pub fn has_users(&self) -> Result<bool, rusqlite::Error> {
+        self.connection.lock().unwrap().query_row(
+            "SELECT EXISTS (SELECT 1 FROM users)",
+            [],
+            |row| row.get(0),
+        )
+    }
+
+    /// Returns None when the username is taken
+    pub fn create_user(
+        &self,
+        username: &str,
+        pass_hash: &str,
+        role: Role,
+    ) -> Result<Option<User>, rusqlite::Error> {
+        let connection = self.connection.lock().unwrap();
+        let created_at = Utc::now().timestamp();
+        let inserted = connection.execute(
+            "INSERT INTO users (username, pass_hash, role, created_at) VALUES (?1, ?2, ?3, ?4)
+            ON CONFLICT (username) DO NOTHING",
+            params![username, pass_hash, role.as_str(), created_at],
+        )?;
+        if inserted == 0 {
+            return Ok(None);
+        }
+        Ok(Some(User {
+            id: connection.last_insert_rowid(),
+            username: username.to_string(),
+            role,
+            created_at,
+        }))
+    }
+
+    pub fn users(&self) -> Result<Vec<User>, rusqlite::Error> {
+        let connection = self.connection.lock().unwrap();
+        let mut statement = connection.prepare("SELECT * FROM users ORDER BY id")?;
+        statement.query_map([], user_from_row)?.collect()
+    }
+
+    /// The user and its password hash
+    pub fn user_by_name(&self, username: &str) -> Result<Option<(User, String)>, rusqlite::Error> {
+        self.connection
+            .lock()
+            .unwrap()
+            .query_row(
+                "SELECT * FROM users WHERE username = ?1",
+                [username],
+                |row| Ok((user_from_row(row)?, row.get("pass_hash")?)),
+            )
+            .optional()
+    }
+
+    pub fn user_pass_hash(&self, id: i64) -> Result<Option<String>, rusqlite::Error> {
+        self.connection
+            .lock()
+            .unwrap()
+            .query_row("SELECT pass_hash FROM users WHERE id = ?1", [id], |row| {
+                row.get(0)
+            })
+            .optional()
+    }
+
+    /// Returns false if the user doesn't exist
+    pub fn set_user_password(&self, id: i64, pass_hash: &str) -> Result<bool, rusqlite::Error> {
+        Ok(self.connection.lock().unwrap().execute(
+            "UPDATE users SET pass_hash = ?1 WHERE id = ?2",
+            params![pass_hash, id],
+        )? > 0)
+    }
+
+    /// Its sessions are deleted with it. The last admin can't be deleted.
+    pub fn delete_user(&self, id: i64) -> Result<UserDeletion, rusqlite::Error> {
+        let mut connection = self.connection.lock().unwrap();
+        let transaction = connection.transaction()?;
+        let role: Option<String> = transaction
+            .query_row("SELECT role FROM users WHERE id = ?1", [id], |row| {
+                row.get(0)
+            })
+            .optional()?;
+        let Some(role) = role else {
+            return Ok(UserDeletion::NotFound);
+        };
+        if Role::parse(&role) == Role::Admin {
+            let admins: i64 = transaction.query_row(
+                "SELECT COUNT(*) FROM users WHERE role = ?1",
+                [Role::Admin.as_str()],
+                |row| row.get(0),
+            )?;
+            if admins <= 1 {
+                return Ok(UserDeletion::LastAdmin);
+            }
+        }
+        transaction.execute("DELETE FROM users WHERE id = ?1", [id])?;
+        transaction.commit()?;
+        Ok(UserDeletion::Deleted)
+    }
+}
@@ -10,6 +10,8 @@ app.use(
     createProxyMiddleware({
         target: 'http://localhost:8080',
         changeOrigin: true,
+        // The server checks the Origin of POST requests against X-Forwarded-Host
+        xfwd: true,
         pathRewrite: {
             '^/api': ''
         }
@@ -1 +1,9 @@
 // place files you want to import through the `$lib` alias in this folder.
+
+/// Header the server requires on the POST & DELETE requests of a session
+export function csrf_headers(): Record<string, string> {
+    const cookie = document.cookie
+        .split('; ')
+        .find((cookie) => cookie.startsWith('csrf_token='));
+    return cookie ? { 'X-CSRF-Token': cookie.slice('csrf_token='.length) } : {};
+}